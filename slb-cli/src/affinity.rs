@@ -0,0 +1,161 @@
+//! Pinning of child processes to CPUs, see `--cpu-affinity`.
+
+use std::fs;
+use std::io::{self, ErrorKind};
+#[cfg(target_os = "linux")]
+use std::mem;
+use std::process::Command;
+use std::str::FromStr;
+
+/// One more than the highest CPU a mask may name, that of `cpu_set_t`.
+const MAX_CPUS: usize = 1024;
+/// Where the kernel lists NUMA nodes.
+const NODE_DIR: &str = "/sys/devices/system/node";
+
+/// Which CPUs child processes run on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CpuAffinity {
+    /// Each child runs on the CPUs of one NUMA node, taking turns by
+    /// index, so that the memory it uses stays local to it.
+    Auto,
+    /// Every child runs on these CPUs.
+    Manual(Vec<usize>),
+}
+
+impl FromStr for CpuAffinity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("manual:") {
+            Some(mask) => parse_mask(mask).map(CpuAffinity::Manual),
+            None if s == "auto" => Ok(CpuAffinity::Auto),
+            None => Err(format!("expected auto or manual:<hex mask>, got {:?}", s)),
+        }
+    }
+}
+
+impl CpuAffinity {
+    /// Returns the sets of CPUs children take turns running on.
+    pub fn cpu_sets(&self) -> io::Result<Vec<Vec<usize>>> {
+        match self {
+            CpuAffinity::Auto => numa_nodes(),
+            CpuAffinity::Manual(cpus) => Ok(vec![cpus.clone()]),
+        }
+    }
+}
+
+/// Parses a hex CPU mask, like `taskset` takes, e.g., `0x3` for CPUs 0
+/// and 1, into the CPUs it has set.
+fn parse_mask(mask: &str) -> Result<Vec<usize>, String> {
+    let digits = mask.strip_prefix("0x").unwrap_or(mask);
+    if digits.is_empty() {
+        return Err("empty CPU mask".to_owned());
+    }
+    let mut cpus = Vec::new();
+    for (i, c) in digits.chars().rev().enumerate() {
+        let digit = c
+            .to_digit(16)
+            .ok_or_else(|| format!("invalid hex digit {:?} in CPU mask", c))?;
+        for bit in 0..4 {
+            if digit & (1 << bit) != 0 {
+                cpus.push(i * 4 + bit);
+            }
+        }
+    }
+    match cpus.last() {
+        None => Err("CPU mask has no CPUs set".to_owned()),
+        Some(&cpu) if cpu >= MAX_CPUS => Err(format!("CPU {} is out of range", cpu)),
+        Some(_) => Ok(cpus),
+    }
+}
+
+/// Reads the CPUs of each NUMA node with any, in node order.
+fn numa_nodes() -> io::Result<Vec<Vec<usize>>> {
+    let mut nodes = Vec::new();
+    for entry in fs::read_dir(NODE_DIR)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let index = match name.to_str().and_then(|name| name.strip_prefix("node")) {
+            Some(index) => index.parse::<usize>().ok(),
+            None => None,
+        };
+        if let Some(index) = index {
+            let list = fs::read_to_string(entry.path().join("cpulist"))?;
+            let cpus = parse_cpu_list(list.trim()).ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidData, format!("bad cpulist {:?}", list))
+            })?;
+            if !cpus.is_empty() {
+                nodes.push((index, cpus));
+            }
+        }
+    }
+    if nodes.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            "no NUMA nodes with CPUs",
+        ));
+    }
+    nodes.sort();
+    Ok(nodes.into_iter().map(|(_, cpus)| cpus).collect())
+}
+
+/// Parses a kernel CPU list, like `0-3,8`.
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus: Vec<usize> = Vec::new();
+    for range in list.split(',').filter(|range| !range.is_empty()) {
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start.parse::<usize>().ok()?, end.parse().ok()?),
+            None => {
+                let cpu: usize = range.parse().ok()?;
+                (cpu, cpu)
+            }
+        };
+        cpus.extend((start..=end).filter(|&cpu| cpu < MAX_CPUS));
+    }
+    Some(cpus)
+}
+
+/// Makes the process `cmd` spawns run only on `cpus`.
+#[cfg(target_os = "linux")]
+pub fn pin(cmd: &mut Command, cpus: &[usize]) {
+    use std::os::unix::process::CommandExt;
+
+    // all zeroes is the empty set
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    for &cpu in cpus {
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    // sched_setaffinity is a plain syscall, so it may run between fork and
+    // exec
+    unsafe {
+        cmd.pre_exec(move || {
+            match libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        });
+    }
+}
+
+/// Returns whether this process, and so its children, may run on any of
+/// `cpus`, assuming it may if that can't be told.
+#[cfg(target_os = "linux")]
+pub fn any_allowed(cpus: &[usize]) -> bool {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    let size = mem::size_of::<libc::cpu_set_t>();
+    if unsafe { libc::sched_getaffinity(0, size, &mut set) } != 0 {
+        return true;
+    }
+    cpus.iter()
+        .any(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+}
+
+// CPU affinity is only supported on Linux, and ignored elsewhere.
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin(_cmd: &mut Command, _cpus: &[usize]) {}
+
+#[cfg(not(target_os = "linux"))]
+pub fn any_allowed(_cpus: &[usize]) -> bool {
+    true
+}
@@ -0,0 +1,86 @@
+//! Checksums of output files, computed as they're written.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh64::Xxh64;
+
+/// A checksum algorithm for `--checksum`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Md5,
+    Sha256,
+    /// 64-bit xxHash, seed 0, as printed by `xxhsum`.
+    Xxhash,
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "md5" => Ok(Algorithm::Md5),
+            "sha256" => Ok(Algorithm::Sha256),
+            "xxhash" => Ok(Algorithm::Xxhash),
+            _ => Err(format!("unknown checksum {:?}", s)),
+        }
+    }
+}
+
+enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+    Xxhash(Xxh64),
+}
+
+/// A writer which checksums everything written through it.
+pub struct ChecksumWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W, algorithm: Algorithm) -> Self {
+        let hasher = match algorithm {
+            Algorithm::Md5 => Hasher::Md5(Md5::new()),
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Xxhash => Hasher::Xxhash(Xxh64::new(0)),
+        };
+        Self { inner, hasher }
+    }
+
+    /// Flushes the writer, returning the hex checksum of all that was
+    /// written.
+    pub fn finish(mut self) -> io::Result<String> {
+        self.inner.flush()?;
+        let digest = match self.hasher {
+            Hasher::Md5(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Xxhash(hasher) => hasher.digest().to_be_bytes().to_vec(),
+        };
+        let mut hex = String::with_capacity(2 * digest.len());
+        for byte in digest {
+            write!(hex, "{:02x}", byte).expect("write to string");
+        }
+        Ok(hex)
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        match &mut self.hasher {
+            Hasher::Md5(hasher) => hasher.update(&buf[..n]),
+            Hasher::Sha256(hasher) => hasher.update(&buf[..n]),
+            Hasher::Xxhash(hasher) => hasher.update(&buf[..n]),
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,4098 @@
+//! `slb` main executable
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::Deref;
+use std::os::fd::AsFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, ChildStderr, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+use std::str::FromStr;
+
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use bstr::io::BufReadExt;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use regex::bytes::Regex;
+use serde_json::json;
+use structopt::StructOpt;
+use tempfile::NamedTempFile;
+
+use slb::cardinality::HyperLogLog;
+use slb::fileblocks::{Compression, FileChunk};
+use slb::sharder::{
+    ConsistentHasher, HashFn, Key, KeyTransform, LineLimit, LineNumbers, LongLines, PartitionMap,
+    ShardMode, ShardOptions, Window, WindowFormat,
+};
+use slb::{fileblocks, pipeline, sharder};
+
+use affinity::CpuAffinity;
+use checksum::ChecksumWriter;
+use progress::{ChunkCursor, Stats};
+use queue::QueueDepths;
+use reload::Reloader;
+use rotate::{CountingWriter, OutputLimit};
+use watchdog::Watchdog;
+
+mod affinity;
+mod checksum;
+mod progress;
+mod queue;
+mod reload;
+mod rotate;
+mod socket;
+mod watchdog;
+
+/// Performs sharded load balancing on stdin, handing off input
+/// to child processes based on a hash of the first word on each line.
+///
+/// E.g., suppose we have a file with contents like
+///
+/// ```
+/// a b c d
+/// e f g h
+/// a b
+/// ```
+///
+/// Every line is handed to stdin of the the [`mapper`] processes,
+/// which are really "flat maps": they can generate multiple output
+/// lines per input line, but should be pure functions.
+///
+/// The above might generate input that looks like
+///
+/// ```
+/// key1 a b c d
+/// key2 e f g h
+/// key1 a b
+/// ```
+///
+/// The key is all bytes leading up to the first space (or the
+/// `--delimiter` byte), or all bytes on a line if there are no spaces.
+/// Suppose `hash(key1) == 1` and `hash(key2) == 2`. For a machine with 2 cores, `slb` will have
+/// two processes, and the zeroth one will receive as stdin
+///
+/// ```
+/// key2 e f g h
+/// ```
+///
+/// since `2 % 2 == 0` and all `key1` lines will be fed into the
+/// process at index 1.
+///
+/// These processes are expected to perform some kind of aggregation
+/// and print at the end of their execution. For instance, suppose we invoke
+/// `slb 'awk -f catter.awk'` where `catter.awk` is just defined to be
+/// `{key = $1; $1 = ""; a[key] += $0}END{for (k in a) print k,a[k]}`,
+/// which just concatenates the keyed values. Then the output might be
+///
+/// ```
+/// <file outprefix0.txt>
+/// key2  e f g h
+///
+/// <file outprefix1.txt>
+/// key1  a b c d a b
+/// ```
+///
+/// If any mapper or folder exits unsuccessfully, `slb` reports which ones
+/// and how, deletes all output, and exits with code 1.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "slb", about = "Performs streaming load balancing.")]
+struct Opt {
+    /// A flat-map pure function, which is a `--shell` command line string
+    /// that performs line-by-line operations on the input to emit
+    /// output lines for reduction.
+    ///
+    /// By default, this is just the identity, equivalent to `cat`.
+    #[structopt(long)]
+    mapper: Option<String>,
+
+    /// Run a different mapper command for the mapper at a given index,
+    /// written `<N>=<cmd>`, e.g., `--mapper-override '0=grep -v ^#'`.
+    ///
+    /// May be repeated. It's an error for an index to be out of range of
+    /// the number of mappers, which is at most `--nthreads` but may be
+    /// fewer for small inputs.
+    #[structopt(long, number_of_values = 1, parse(try_from_str = parse_override))]
+    mapper_override: Vec<(usize, String)>,
+
+    /// Tell each mapper which chunk of the input it has through its
+    /// environment: `SLB_CHUNK_INDEX`, the byte range `SLB_CHUNK_START` to
+    /// `SLB_CHUNK_STOP` (empty for a chunk read to EOF), and `SLB_NCHUNKS`,
+    /// e.g., to seed a random number generator per chunk.
+    #[structopt(long)]
+    mapper_env_per_chunk: bool,
+
+    /// Tell each folder which partition it has through its environment:
+    /// `SLB_PARTITION_INDEX`, `SLB_NPARTITIONS`, and `SLB_OUTPUT_PATH`, the
+    /// absolute path of its output file (empty without `--outprefix`),
+    /// e.g., to write an index next to it at `$SLB_OUTPUT_PATH.index`.
+    #[structopt(long)]
+    folder_env_per_partition: bool,
+
+    /// Run this many instances of each mapper, dealing out blocks of
+    /// lines from its chunk among them and merging their output before
+    /// sharding, for a CPU-bound, single-threaded mapper on a machine
+    /// with more cores than chunks.
+    ///
+    /// Like `--input-line-filter`, this feeds mappers over a pipe.
+    #[structopt(long, default_value = "1", parse(try_from_str = parse_positive))]
+    mapper_workers: usize,
+
+    /// Feed each mapper at most this many lines, then close its input and
+    /// start a new one for the next batch, for mappers which work on
+    /// whole batches, e.g., model inference, only keeping a batch and its
+    /// output in memory.
+    ///
+    /// Like `--input-line-filter`, this feeds mappers over a pipe.
+    #[structopt(
+        long,
+        parse(try_from_str = parse_positive),
+        conflicts_with_all = &["mapper-workers", "mapper-timeout", "mapper-output-passthrough"]
+    )]
+    batch_size: Option<usize>,
+
+    /// Send mapper i's output straight to folder i, over a single pipe
+    /// between the two, without sharding it, for mappers whose output
+    /// is already partitioned, e.g., by their input.
+    ///
+    /// There must be as many folders as mappers, each with one worker, and
+    /// nothing which needs the lines in between, like a `--combiner`.
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "combiner",
+            "presort",
+            "folder-stdin-prefix",
+            "folder-stdin-suffix",
+            "socket-folder",
+            "window",
+            "split-points-file",
+            "infile-partition",
+            "verify",
+            "estimate-cardinality",
+            "progress-bar"
+        ]
+    )]
+    mapper_output_passthrough: bool,
+
+    /// A partial folder function, which is a `--shell` command line string run
+    /// on each buffer of sharded mapper output before it's sent to its
+    /// folder, like a MapReduce combiner.
+    ///
+    /// Its output replaces the buffer, so it must emit lines the folder
+    /// can consume, e.g., partial counts for a folder that sums them.
+    /// A new process is started per buffer, so consider raising
+    /// `--bufsize`. If it fails, nothing more is sent from that mapper
+    /// and `slb` exits with an error.
+    #[structopt(long)]
+    combiner: Option<String>,
+
+    /// Sort each folder's input by key, through `sort`, so that all lines
+    /// with the same key arrive contiguously, which lets folders aggregate
+    /// one key at a time rather than holding every key in memory.
+    ///
+    /// Only keys up to a `--delimiter` are supported. Since `sort` needs
+    /// all of its input first, folders then only start once all mappers
+    /// are done.
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "key-fields",
+            "key-regex",
+            "key-byte-range",
+            "key-jsonptr",
+            "key-transform"
+        ]
+    )]
+    presort: bool,
+
+    /// Bytes to write to each folder's stdin before any lines, e.g., to
+    /// initialize a streaming SQL processor: either hex-encoded, like
+    /// `0a` for a newline, or `@<path>` to take the contents of a file.
+    #[structopt(
+        long,
+        parse(try_from_str = parse_stdin_bytes),
+        conflicts_with_all = &["presort", "socket-folder"]
+    )]
+    folder_stdin_prefix: Option<Bytes>,
+
+    /// Bytes to write to each folder's stdin after all lines, e.g., an
+    /// end-of-stream sentinel, given like `--folder-stdin-prefix`.
+    #[structopt(
+        long,
+        parse(try_from_str = parse_stdin_bytes),
+        conflicts_with_all = &["presort", "socket-folder"]
+    )]
+    folder_stdin_suffix: Option<Bytes>,
+
+    /// On SIGUSR1, replace the folders with new ones running the command
+    /// in `--hot-reload-config`, for long-running streams whose folders
+    /// need updating without restarting `slb`.
+    ///
+    /// Each folder is replaced before it's next sent lines, once the old
+    /// one has finished with everything sent to it, and the new one keeps
+    /// writing to the same output. Lines are still routed as before.
+    #[structopt(
+        long,
+        requires = "hot-reload-config",
+        conflicts_with_all = &[
+            "presort",
+            "socket-folder",
+            "output-compression",
+            "checksum",
+            "max-output-bytes",
+            "max-output-lines",
+            "folder-timeout",
+            "capture-child-stderr"
+        ]
+    )]
+    hot_reload: bool,
+
+    /// The file to read the new folder command from for `--hot-reload`.
+    #[structopt(long, requires = "hot-reload")]
+    hot_reload_config: Option<PathBuf>,
+
+    /// Pipe each folder's output through this command before it's written
+    /// out, e.g., `sort -k1,1` for sorted output files. Its failure fails
+    /// the run like the folder's own.
+    #[structopt(long, conflicts_with_all = &["socket-folder", "hot-reload", "folder-restart"])]
+    pipe_output_through: Option<String>,
+
+    /// Restart a folder up to this many times if it exits before it's
+    /// been sent all its lines, e.g., for a flaky folder that occasionally
+    /// crashes, rather than failing the whole run.
+    ///
+    /// The new folder keeps writing to the same output and gets every line
+    /// from those the old one failed to take onward, so lines it had read
+    /// but not yet folded are lost.
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "presort",
+            "socket-folder",
+            "output-compression",
+            "checksum",
+            "max-output-bytes",
+            "max-output-lines",
+            "folder-timeout",
+            "capture-child-stderr"
+        ]
+    )]
+    folder_restart: Option<usize>,
+
+    /// The folder function.
+    ///
+    /// Multiple instances of this same process are created with the same
+    /// command-line string. Text lines from mapper output are fed
+    /// into these processes, and stdout is shared between this parent
+    /// process and its children, but collated.
+    ///
+    /// Given more than once, every folder command gets all of each
+    /// partition's lines, e.g., to both aggregate them and archive them,
+    /// and folder M of partition N writes to `<outprefix>N_folderM`.
+    #[structopt(
+        long,
+        number_of_values = 1,
+        required_unless_one = &["benchmark", "output-per-key"]
+    )]
+    folder: Vec<String>,
+
+    /// The input files to read lines from.
+    ///
+    /// Arguments containing `*`, `?` or `[` are expanded as glob
+    /// patterns, e.g., `--infile 'data/*.tsv'`, and it's an error for a
+    /// pattern to match nothing.
+    ///
+    /// If no input files or directories are given, stdin is read instead.
+    /// Since stdin can't be split into chunks directly, it is first
+    /// buffered in its entirety into a temporary file (in `$TMPDIR`), so
+    /// make sure there's space.
+    ///
+    /// Named pipes, e.g., `--infile <(curl ...)`, are instead streamed to
+    /// a single mapper each, unless there's a header to skip.
+    #[structopt(long)]
+    infile: Vec<PathBuf>,
+
+    /// Directories to recursively read all files under, in addition to
+    /// any `--infile`s. All input files are read in sorted order.
+    #[structopt(long)]
+    indir: Vec<PathBuf>,
+
+    /// Input files that are already partitioned, e.g., output of an
+    /// earlier `slb` run, written `<N>:<path>`. Mapper output for these
+    /// isn't sharded, but all goes to folder `N`.
+    ///
+    /// May be repeated. It's an error for an index to be out of range of
+    /// the number of folders.
+    #[structopt(long, number_of_values = 1, parse(try_from_str = parse_infile_partition))]
+    infile_partition: Vec<(usize, PathBuf)>,
+
+    /// The compression of all input files, one of `none`, `gzip` or
+    /// `zstd`.
+    ///
+    /// By default, this is detected per file from its extension (`.gz`
+    /// or `.zst`) or leading magic bytes. Gzip files, and zstd files not
+    /// in the seekable format, can't be split, so each is read by a
+    /// single mapper.
+    #[structopt(long, possible_values = &["none", "gzip", "zstd"])]
+    compression: Option<Compression>,
+
+    /// Drop input lines matching this regex before they reach the
+    /// mappers, saving a `grep` in each mapper just to filter.
+    ///
+    /// Filtered input is fed to mappers over a pipe, so it doesn't count
+    /// towards `--progress`.
+    #[structopt(long, parse(try_from_str = Regex::new))]
+    input_line_filter: Option<Regex>,
+
+    /// Keep only the input lines matching `--input-line-filter` instead.
+    #[structopt(long, requires = "input-line-filter")]
+    input_line_filter_invert: bool,
+
+    /// Drop this many lines from the start of the first input file, e.g.,
+    /// the header of a CSV or TSV file, rather than sharding them.
+    #[structopt(long)]
+    input_skip_lines: Option<usize>,
+
+    /// Drop the first line of the first input file, its header, like
+    /// `--input-skip-lines 1`.
+    #[structopt(long, conflicts_with = "input-skip-lines")]
+    input_skip_header: bool,
+
+    /// Write the lines dropped by `--input-skip-lines` or
+    /// `--input-skip-header` to this file, e.g., to prepend them to the
+    /// `--merge-output`.
+    #[structopt(long)]
+    header_output: Option<PathBuf>,
+
+    /// Feed mappers only a random sample of input lines, each kept
+    /// independently with this probability, e.g., `0.01` to try out a
+    /// pipeline on 1% of the data.
+    ///
+    /// Like `--input-line-filter`, this feeds mappers over a pipe.
+    #[structopt(long, parse(try_from_str = parse_sample_rate))]
+    sample_rate: Option<f64>,
+
+    /// Seed `--sample-rate` so that the same lines are sampled every run;
+    /// each mapper's sample is seeded from this and its index.
+    #[structopt(long, requires = "sample-rate")]
+    seed: Option<u64>,
+
+    /// Shuffle the order of all input lines before sharding them, e.g., so
+    /// that mappers with a time limit each see a fair sample of sorted
+    /// input.
+    ///
+    /// The whole input (after any skipped lines, but not
+    /// `--infile-partition` files) is read into memory to shuffle it,
+    /// taking about its decompressed size plus 16 bytes per line, and
+    /// mappers then read the shuffled lines from a temporary file.
+    #[structopt(long)]
+    input_shuffle: bool,
+
+    /// Seed `--input-shuffle` so lines are shuffled the same way every run.
+    #[structopt(long, requires = "input-shuffle")]
+    shuffle_seed: Option<u64>,
+
+    /// Output file prefixes.
+    ///
+    /// If omitted, there's a single folder by default, writing straight
+    /// to `slb`'s stdout, e.g., for `slb --folder sort | downstream`.
+    /// It's an error to ask for more folders without an output prefix or
+    /// `--merge-output`.
+    #[structopt(long)]
+    outprefix: Option<PathBuf>,
+
+    /// Write all folders' output to this one file instead, all of folder
+    /// 0's output first, then folder 1's, and so on.
+    ///
+    /// Until its turn, the output of each folder after the first is
+    /// spooled to a temporary file (in `$TMPDIR`).
+    #[structopt(long, conflicts_with = "outprefix")]
+    merge_output: Option<PathBuf>,
+
+    /// Like `--merge-output`, but write all folders' output to `slb`'s
+    /// stdout, e.g., for `slb --folder sort --folder-output-to-stdout |
+    /// sort -m`.
+    #[structopt(long, conflicts_with_all = &["outprefix", "merge-output"])]
+    folder_output_to_stdout: bool,
+
+    /// Send each folder's input over the Unix domain socket
+    /// `<outprefix>N.sock` to a persistent folder server instead, which
+    /// keeps a single `--folder` process running across `slb` runs, so it
+    /// can keep state in memory and doesn't start up every time.
+    ///
+    /// For any socket nothing's listening on, a server is started in the
+    /// background, appending its folder's output to `<outprefix>N`. The
+    /// folder sees the end of its input only once its server is killed,
+    /// e.g., with `pkill -f 'serve-folder <outprefix>N.sock'`, and output
+    /// isn't deleted on failure in this mode.
+    #[structopt(
+        long,
+        requires = "outprefix",
+        conflicts_with_all = &["atomic", "output-compression", "presort", "folder-timeout"]
+    )]
+    socket_folder: bool,
+
+    /// Serve a persistent folder on this socket, see `--socket-folder`.
+    #[structopt(long, hidden = true, requires = "serve-output")]
+    serve_folder: Option<PathBuf>,
+
+    /// Where a served folder's output is appended.
+    #[structopt(long, hidden = true)]
+    serve_output: Option<PathBuf>,
+
+    /// Compress each output file with `gzip` or `zstd`, adding a `.gz` or
+    /// `.zst` extension to its name.
+    #[structopt(long, possible_values = &["gzip", "zstd"], requires = "outprefix")]
+    output_compression: Option<Compression>,
+
+    /// The `--output-compression` level, from 0 to 9 for gzip (default
+    /// 6) or 1 to 22 for zstd (default 3).
+    #[structopt(long, requires = "output-compression")]
+    compression_level: Option<u32>,
+
+    /// How folder output lines are written out: `plain` as the folders
+    /// wrote them, or `json` with each wrapped in a
+    /// `{"partition":N,"line":"..."}` object, where `N` is the index of
+    /// the folder that wrote it, for JSON-consuming systems downstream.
+    ///
+    /// Defaults to `plain`. Lines that aren't UTF-8 have their invalid
+    /// bytes replaced, and the `--output-header` isn't wrapped.
+    #[structopt(
+        long,
+        possible_values = &["plain", "json"],
+        requires = "outprefix",
+        conflicts_with_all = &["socket-folder", "hot-reload", "folder-restart"]
+    )]
+    output_format: Option<OutputFormat>,
+
+    /// Rather than folding lines into a fixed number of partitions, write
+    /// mapper output lines to one file per distinct key, named
+    /// `<outprefix>_<key>.txt`, e.g., for a few dozen country codes.
+    ///
+    /// Bytes of keys other than ASCII letters, digits, `-`, `.` and `_`
+    /// are written `%XX` in file names. Lines without a key go with those
+    /// whose key is empty.
+    #[structopt(
+        long,
+        requires = "outprefix",
+        conflicts_with_all = &[
+            "folder",
+            "nfolders",
+            "split-points-file",
+            "partition-map-file",
+            "socket-folder",
+            "presort",
+            "hot-reload",
+            "folder-restart",
+            "pipe-output-through",
+            "output-compression",
+            "checksum",
+            "max-output-bytes",
+            "max-output-lines",
+            "merge-output",
+            "folder-output-to-stdout",
+            "folder-timeout",
+            "output-format",
+            "output-header-file",
+            "atomic",
+            "append",
+            "verify",
+            "mapper-output-passthrough",
+            "folder-stdin-prefix",
+            "folder-stdin-suffix",
+            "infile-partition",
+        ]
+    )]
+    output_per_key: bool,
+
+    /// With `--output-per-key`, fail once there would be more than this
+    /// many output files, rather than running out of file descriptors on
+    /// unexpectedly many keys.
+    ///
+    /// Defaults to 1000.
+    #[structopt(long, parse(try_from_str = parse_positive), requires = "output-per-key")]
+    max_output_files: Option<usize>,
+
+    /// Checksum each output file with `md5`, `sha256` or `xxhash` as it's
+    /// written, printing a `<checksum>  <path>` line for each to stdout
+    /// once all succeed, as `md5sum -c`, `sha256sum -c` or `xxhsum -c`
+    /// would check it.
+    ///
+    /// A compressed file's checksum covers its compressed bytes.
+    #[structopt(
+        long,
+        possible_values = &["md5", "sha256", "xxhash"],
+        requires = "outprefix",
+        conflicts_with_all = &["append", "socket-folder"]
+    )]
+    checksum: Option<checksum::Algorithm>,
+
+    /// Start each output file with the contents of this file, e.g., the
+    /// column names of CSV output, before anything its folder writes.
+    ///
+    /// Each of the files `--max-output-bytes` or `--max-output-lines` split
+    /// output into starts with it, while `--merge-output` only does once.
+    #[structopt(long, conflicts_with_all = &["append", "socket-folder"])]
+    output_header_file: Option<PathBuf>,
+
+    /// Write the `--checksum` lines to this file rather than stdout.
+    #[structopt(long, requires = "checksum")]
+    checksum_file: Option<PathBuf>,
+
+    /// Move on to a new output file, `<outprefix>N_1`, then
+    /// `<outprefix>N_2`, and so on, before a line that would take a
+    /// folder's current one over this many bytes.
+    #[structopt(
+        long,
+        parse(try_from_str = parse_positive),
+        requires = "outprefix",
+        conflicts_with_all = &[
+            "output-compression",
+            "checksum",
+            "atomic",
+            "append",
+            "socket-folder",
+            "verify"
+        ]
+    )]
+    max_output_bytes: Option<usize>,
+
+    /// Like `--max-output-bytes`, but limits the lines in each output file.
+    #[structopt(
+        long,
+        parse(try_from_str = parse_positive),
+        requires = "outprefix",
+        conflicts_with_all = &[
+            "output-compression",
+            "checksum",
+            "atomic",
+            "append",
+            "socket-folder",
+            "verify"
+        ]
+    )]
+    max_output_lines: Option<usize>,
+
+    /// Write each output file to a `.tmp` sibling first, renaming it into
+    /// place only after all mappers and folders succeed, and deleting it
+    /// otherwise.
+    ///
+    /// Downstream consumers then see either complete output or none.
+    #[structopt(long, requires = "outprefix")]
+    atomic: bool,
+
+    /// Append to existing output files rather than truncating them.
+    ///
+    /// Together with a fixed `--hasher`, this adds data to a previously
+    /// sharded dataset, as long as the number of folders is unchanged.
+    /// Beware that re-running without `--append` silently drops the
+    /// previous contents. Output isn't deleted on interrupt or failure in
+    /// this mode, since partially appended files can't be restored.
+    #[structopt(long, conflicts_with = "atomic", requires = "outprefix")]
+    append: bool,
+
+    /// Buffer size in KB for buffering output before it's sent to
+    /// folders from a mapper.
+    ///
+    /// Note memory usage is O(bufsize * nthreads).
+    #[structopt(long)]
+    bufsize: Option<usize>,
+
+    /// Size `--bufsize` to the memory available, as read from
+    /// `/proc/meminfo`: a quarter of it split across `--nthreads`, but
+    /// between 64 KB and 256 MB. It's printed with `--verbose`.
+    ///
+    /// This is only a heuristic, which ignores what the mappers and folders
+    /// need themselves, so an explicit `--bufsize` takes precedence.
+    #[structopt(long)]
+    auto_bufsize: bool,
+
+    /// Apply `--bufsize` to each folder's buffer within a mapper, rather
+    /// than to their total, which avoids many small sends with many
+    /// folders.
+    ///
+    /// Memory usage is then O(bufsize * nthreads * nfolders).
+    #[structopt(long)]
+    bufsize_per_partition: bool,
+
+    /// Also send buffered mapper output to folders once this many seconds
+    /// have passed since the last send, even if the buffer isn't full.
+    ///
+    /// This keeps lines for rarely-hit folders from lingering, e.g., when
+    /// folders act on lines as they arrive.
+    #[structopt(long)]
+    flush_interval: Option<f64>,
+
+    /// Records in the input and in mapper output are terminated by null
+    /// bytes rather than newlines, like `find -print0` output or
+    /// `sort -z` input.
+    ///
+    /// Keys are then extracted from each record without its `\0`, and a
+    /// final unterminated record gets one added.
+    #[structopt(short = "z", long)]
+    null: bool,
+
+    /// Handle lines of mapper output longer than this many bytes,
+    /// excluding the terminator, according to `--on-long-line`.
+    #[structopt(long, parse(try_from_str = parse_positive))]
+    max_line_length: Option<usize>,
+
+    /// What to do with lines over `--max-line-length`: `truncate` them to
+    /// it or `skip` them, either way printing a warning with their count,
+    /// or exit with an `error`.
+    #[structopt(
+        long,
+        default_value = "error",
+        possible_values = &["truncate", "error", "skip"]
+    )]
+    on_long_line: LongLines,
+
+    /// The single byte which separates the key from the rest of each
+    /// line of mapper output, e.g., `$'\t'` for TSV input, or the fields
+    /// from each other with `--key-fields`.
+    ///
+    /// Besides a literal byte, the escapes `\t` and `\0` are accepted.
+    /// Defaults to a space.
+    #[structopt(long, parse(try_from_str = parse_delimiter))]
+    delimiter: Option<u8>,
+
+    /// Key each line of mapper output by these comma-separated, 1-indexed
+    /// fields instead of just the first, e.g., `--key-fields 1,3`.
+    ///
+    /// Fields are split by `--delimiter`, and ones past the end of a line
+    /// are skipped.
+    #[structopt(long, use_delimiter = true, parse(try_from_str = parse_field))]
+    key_fields: Vec<usize>,
+
+    /// Key each line of mapper output by the first capture group of this
+    /// regex, e.g., `'user=(\w+)'`, or by the whole match if it has no
+    /// groups.
+    ///
+    /// Lines that don't match are keyed by the whole line.
+    #[structopt(
+        long,
+        conflicts_with_all = &["delimiter", "key-fields"],
+        parse(try_from_str = Regex::new)
+    )]
+    key_regex: Option<Regex>,
+
+    /// Key each line of mapper output by the bytes at offsets
+    /// `<start>:<end>` (0-indexed, end exclusive), for fixed-width
+    /// records.
+    ///
+    /// Lines are keyed by however much of the range they cover, so lines
+    /// shorter than `start` all share the empty key.
+    #[structopt(
+        long,
+        conflicts_with_all = &["delimiter", "key-fields", "key-regex"],
+        parse(try_from_str = parse_byte_range)
+    )]
+    key_byte_range: Option<(usize, usize)>,
+
+    /// Treat each line of mapper output as a JSON document, keyed by the
+    /// value at this JSON Pointer (RFC 6901), e.g., `/user/id`.
+    ///
+    /// Lines that aren't valid JSON or lack the pointed-to value all go
+    /// to folder 0, and a warning with their count is printed to stderr.
+    #[structopt(
+        long,
+        conflicts_with_all = &["delimiter", "key-fields", "key-regex", "key-byte-range"],
+        parse(try_from_str = parse_jsonptr)
+    )]
+    key_jsonptr: Option<String>,
+
+    /// Normalize each key before it's hashed, so that, e.g., with
+    /// `lowercase`, `Apple` and `apple` go to the same folder: one of
+    /// `lowercase` or `uppercase` (of ASCII letters) or `trim`,
+    /// `trim-start` or `trim-end` (of Unicode whitespace at both ends, the
+    /// start or the end).
+    ///
+    /// May be repeated to apply several in order. Lines are passed along
+    /// unchanged.
+    #[structopt(
+        long,
+        number_of_values = 1,
+        possible_values = &["lowercase", "uppercase", "trim", "trim-start", "trim-end"]
+    )]
+    key_transform: Vec<KeyTransform>,
+
+    /// Lowercase the ASCII letters of each key, so that, e.g., `Alice`,
+    /// `ALICE` and `alice` go to the same folder, like
+    /// `--key-transform lowercase` after any other `--key-transform`.
+    #[structopt(long)]
+    key_lowercase: bool,
+
+    /// Strip leading and trailing Unicode whitespace from each key, so that,
+    /// e.g., ` alice ` and `alice` go to the same folder, like
+    /// `--key-transform trim` after any other `--key-transform`.
+    #[structopt(long, conflicts_with_all = &["key-trim-start", "key-trim-end"])]
+    key_trim: bool,
+
+    /// Like `--key-trim`, but only strips leading whitespace.
+    #[structopt(long)]
+    key_trim_start: bool,
+
+    /// Like `--key-trim`, but only strips trailing whitespace.
+    #[structopt(long)]
+    key_trim_end: bool,
+
+    /// Send up to this many consecutive lines with the same key on
+    /// together, as a single line in the `--window-format`, so that
+    /// folders see lines in context, e.g., for sequence models. A window
+    /// ends early when the key changes.
+    ///
+    /// With `--shard-mode roundrobin`, keys are ignored, and every window
+    /// but the last has this many lines.
+    #[structopt(long, parse(try_from_str = parse_positive), conflicts_with = "verify")]
+    window: Option<usize>,
+
+    /// How `--window` joins lines: `tab` separates them by tabs, and
+    /// `json` makes a JSON array of them, as strings.
+    #[structopt(long, default_value = "tab", possible_values = &["tab", "json"])]
+    window_format: WindowFormat,
+
+    /// Prefix each line sent to a folder with `<number>\t`, numbering the
+    /// lines of all mappers' output together, in the order they're routed,
+    /// e.g., for debugging or to later put the lines back in order.
+    ///
+    /// The key is still that of the unprefixed line.
+    #[structopt(long, conflicts_with_all = &["verify", "presort", "mapper-output-passthrough"])]
+    line_number_prefix: bool,
+
+    /// Include the index of the chunk each line came from in
+    /// `--line-number-prefix`, as `<chunk>:<number>\t`.
+    #[structopt(long, requires = "line-number-prefix")]
+    line_number_prefix_source: bool,
+
+    /// Capacity, in buffers, of the queue feeding each folder, beyond which
+    /// mappers block.
+    ///
+    /// With `--verbose`, the maximum depth each queue reached is printed.
+    /// Regardless, a warning is printed if all queues are ever full at
+    /// once.
+    #[structopt(long, default_value = "256", parse(try_from_str = parse_positive))]
+    queuesize: usize,
+
+    /// The hash function used to assign keys to folders.
+    ///
+    /// All choices are stable across platforms and `slb` runs, so the
+    /// same key always lands on the same folder index given the same
+    /// number of folders.
+    #[structopt(long, default_value = "siphash", possible_values = &["fnv", "xxhash", "siphash"])]
+    hasher: HashFn,
+
+    /// How lines are routed to folders: `hash` sends all lines with the
+    /// same key to the same folder, while `roundrobin` ignores keys and
+    /// deals lines out evenly, which suits embarrassingly parallel
+    /// workloads like filtering.
+    #[structopt(long, default_value = "hash", possible_values = &["hash", "roundrobin"])]
+    shard_mode: Routing,
+
+    /// With `--shard-mode hash`, assign keys to folders by jump consistent
+    /// hashing of `--hasher` hashes, so that re-running with more folders
+    /// only moves keys into the new folders.
+    ///
+    /// For instance, every key of folder `i` out of 8 lands in either
+    /// folder `i` or one of folders 8 to 15 out of 16.
+    #[structopt(long)]
+    consistent_hash: bool,
+
+    /// Partition keys by ranges rather than hashes, split at the sorted
+    /// keys listed one per line in this file, which makes for one more
+    /// folder than there are lines.
+    ///
+    /// Folder 0 gets keys less than the first split point, folder 1 keys
+    /// from it up to the second, and so on, comparing keys bytewise like
+    /// `LC_ALL=C sort`.
+    #[structopt(long, conflicts_with_all = &["consistent-hash", "nfolders", "null"])]
+    split_points_file: Option<PathBuf>,
+
+    /// Route lines with the keys listed in this file to the folders
+    /// listed next to them, e.g., to give a hot key a folder of its own.
+    /// Each line holds a key and a folder index separated by whitespace;
+    /// all other keys are routed as usual.
+    #[structopt(long, conflicts_with = "split-points-file")]
+    partition_map_file: Option<PathBuf>,
+
+    /// Send each run of consecutive lines of mapper output with the same
+    /// key to its folder as soon as the key changes, rather than once
+    /// `--bufsize` fills up, e.g., for mappers whose output is grouped by
+    /// key.
+    ///
+    /// Runs are still routed by `--shard-mode`, but are held in memory
+    /// until they end, however long they get.
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "split-points-file",
+            "partition-map-file",
+            "window",
+            "line-number-prefix",
+            "null",
+            "flush-interval",
+            "max-line-length",
+            "skew-threshold",
+            "mapper-output-passthrough",
+        ]
+    )]
+    split_on_key_change: bool,
+
+    /// Relay the stderr of mapper and folder processes line by line,
+    /// prefixed with `[mapper-N]` or `[folder-N]`, instead of letting it
+    /// interleave with `slb`'s own stderr.
+    #[structopt(long)]
+    capture_child_stderr: bool,
+
+    /// The shell that runs mapper, combiner and folder command lines, as
+    /// `<shell> -c <cmd>`.
+    ///
+    /// Defaults to `$SHELL`, or `/bin/sh` if that's unset.
+    #[structopt(long)]
+    shell: Option<PathBuf>,
+
+    /// Set an environment variable for every mapper, combiner and folder
+    /// process, written `<KEY>=<VALUE>`, e.g., `--env LC_ALL=C`.
+    ///
+    /// May be repeated.
+    #[structopt(long, number_of_values = 1, parse(try_from_str = parse_env))]
+    env: Vec<(String, String)>,
+
+    /// Start child processes with an empty environment, besides any
+    /// `--env` variables, for hermetic runs.
+    #[structopt(long)]
+    clear_env: bool,
+
+    /// After all input is processed, print an estimate of the number of
+    /// distinct keys to stderr, along with the exact numbers of lines and
+    /// bytes, to help pick a number of folders.
+    #[structopt(long)]
+    estimate_cardinality: bool,
+
+    // TODO: consider sort-like KEYDEF -k --key which wouldn't hash if n (numeric) flag set
+    /// Print debug information to stderr.
+    #[structopt(long)]
+    verbose: bool,
+
+    /// Drop mapper output lines whose key is empty or all whitespace, such
+    /// as lines of nothing but spaces, rather than sending them all to the
+    /// same folder.
+    #[structopt(long, conflicts_with = "mapper-output-passthrough")]
+    skip_empty_keys: bool,
+
+    /// Write the lines dropped by `--skip-empty-keys` to this file.
+    #[structopt(long, requires = "skip-empty-keys")]
+    discard_file: Option<PathBuf>,
+
+    /// After a successful run, write statistics as JSON to this file: the
+    /// commands, `--nthreads` and the number of chunks, the bytes and lines
+    /// of mapper output read, the lines, bytes and blocking sends for each
+    /// folder, and the wall time.
+    #[structopt(long)]
+    stats_file: Option<PathBuf>,
+
+    /// After a successful run, re-read every output file and check that
+    /// each line's key belongs in that folder, printing any misrouted
+    /// lines to stderr and failing if there are any. This only makes sense
+    /// for folders that pass lines through, like `sort`, to catch hash
+    /// function bugs or version skew.
+    #[structopt(
+        long,
+        requires = "outprefix",
+        conflicts_with_all = &["output-compression", "socket-folder", "infile-partition"]
+    )]
+    verify: bool,
+
+    /// Warn on stderr, with the most frequent keys, when the coefficient
+    /// of variation of line counts across folders exceeds this, e.g.,
+    /// `2.0`, which indicates a few hot keys are overloading some folders.
+    ///
+    /// Each mapper checks the lines it has sent after every buffer flush.
+    #[structopt(long)]
+    skew_threshold: Option<f64>,
+
+    /// Print the number of chunks and folders, and what each mapper and
+    /// folder would run on, then exit without running anything or
+    /// creating any output.
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Instead of reading any input or running anything, time sharding
+    /// synthetic lines like `key_000042 value_000042` with the given key
+    /// and buffer options, discarding the output, and print its
+    /// throughput. This measures the overhead of `slb` itself, apart from
+    /// mappers and folders.
+    #[structopt(long)]
+    benchmark: bool,
+
+    /// The number of `--benchmark` lines.
+    #[structopt(long, default_value = "1000000", parse(try_from_str = parse_positive))]
+    bench_nlines: usize,
+
+    /// The size of each `--benchmark` line in bytes, including its
+    /// terminator, padded out from its natural size, which it's at least.
+    #[structopt(long, default_value = "64")]
+    bench_line_size: usize,
+
+    /// Print the `--dry-run` report as JSON.
+    #[structopt(long, requires = "dry-run")]
+    dry_run_json: bool,
+
+    /// Feed uncompressed input to mappers from memory maps instead of
+    /// having them read the input files directly, which can be faster on
+    /// local SSDs. Progress isn't tracked for memory-mapped input.
+    #[cfg(feature = "mmap")]
+    #[structopt(long)]
+    mmap: bool,
+
+    /// Print progress to stderr every second: input bytes read, mapper
+    /// output sent to folders, and how often sends blocked on each folder.
+    ///
+    /// The throughput and ETA only account for uncompressed input.
+    #[structopt(long)]
+    progress: bool,
+
+    /// Show progress bars on stderr instead, if it's a terminal: the
+    /// bytes read of each (uncompressed) input file, and the lines
+    /// mappers have sent and folders were fed.
+    #[structopt(long, conflicts_with = "progress")]
+    progress_bar: bool,
+
+    /// Seconds to wait for children and threads to wind down after an
+    /// interrupt (SIGINT), before exiting forcefully.
+    ///
+    /// Either way, partially-written output files are deleted and `slb`
+    /// exits with code 130.
+    #[structopt(long, default_value = "5")]
+    shutdown_timeout: f64,
+
+    /// Seconds after which mappers still running are killed, with SIGTERM
+    /// and then, 5 seconds later, SIGKILL, failing the run.
+    ///
+    /// This kills the whole mapper command line, which runs in its own
+    /// process group as a result.
+    #[structopt(long)]
+    mapper_timeout: Option<f64>,
+
+    /// What to do about a failed mapper: `abort` fails the run, once it's
+    /// done; `skip` drops the mapper's output, with a warning; and `retry`
+    /// re-runs it up to `--mapper-retry-count` times, waiting 100ms before
+    /// the first retry and twice as long before each one after.
+    ///
+    /// With `skip` or `retry`, each mapper's output is spooled to a
+    /// temporary file (in `$TMPDIR`) and only sharded once it succeeds.
+    /// Mappers reading named pipes are never retried.
+    #[structopt(long, default_value = "abort", possible_values = &["abort", "skip", "retry"])]
+    on_mapper_failure: OnMapperFailure,
+
+    /// How many times `--on-mapper-failure retry` re-runs a mapper.
+    #[structopt(long, default_value = "3")]
+    mapper_retry_count: usize,
+
+    /// Like `--mapper-timeout`, but for folders.
+    #[structopt(long)]
+    folder_timeout: Option<f64>,
+
+    /// Limit the address space of each mapper process to this many bytes,
+    /// with `setrlimit(RLIMIT_AS)`, so a runaway mapper fails rather than
+    /// taking the machine down with it.
+    ///
+    /// Processes over the limit fail to allocate memory, and usually exit
+    /// or abort. One killed outright, with SIGKILL, is reported as over its
+    /// memory limit.
+    #[structopt(long, parse(try_from_str = parse_positive))]
+    max_mapper_memory: Option<usize>,
+
+    /// Like `--max-mapper-memory`, but for folders.
+    #[structopt(long, parse(try_from_str = parse_positive), conflicts_with = "socket-folder")]
+    max_folder_memory: Option<usize>,
+
+    /// Pin mapper and folder processes to CPUs: with `auto`, each runs on
+    /// the CPUs of one NUMA node, mappers and folders each taking turns
+    /// across nodes by index, so that their memory stays local on NUMA
+    /// systems; with `manual:<mask>`, all run on the CPUs set in the hex
+    /// mask, e.g., `manual:0xf` for CPUs 0 to 3, as `taskset` takes.
+    ///
+    /// Only supported on Linux, and ignored elsewhere with a warning.
+    #[structopt(long, conflicts_with = "socket-folder")]
+    cpu_affinity: Option<CpuAffinity>,
+
+    // TODO: this isn't very useful as an option, consider removing entirely
+    // or allowing a max_mappers and max_folders which controls maximum
+    // concurrency
+    /// Approximate target number of parallel threads per stage to launch:
+    /// the total number of live threads could be a multiple of this becuase
+    /// of concurrent mapper and folder stages.
+    ///
+    /// Defaults to num CPUs.
+    #[structopt(long)]
+    nthreads: Option<usize>,
+
+    /// Run at most this many mappers, however many input files there are,
+    /// rather than at least one per file, e.g., for many small files.
+    ///
+    /// When there are more files, consecutive ones are merged into a
+    /// temporary file per mapper first, which copies the input. Folders
+    /// still default to one per mapper, see `--nfolders`.
+    #[structopt(long, parse(try_from_str = parse_positive))]
+    max_chunks: Option<usize>,
+
+    /// Number of folders, and so output files, which defaults to the
+    /// number of mappers, or 1 without `--outprefix`, `--merge-output`, or
+    /// `--folder-output-to-stdout`.
+    /// Fold-heavy workloads may benefit from more.
+    #[structopt(long, parse(try_from_str = parse_positive))]
+    nfolders: Option<usize>,
+}
+
+fn parse_positive(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(0) | Err(_) => Err(format!("expected a positive integer, got {:?}", s)),
+        Ok(n) => Ok(n),
+    }
+}
+
+fn parse_stdin_bytes(s: &str) -> Result<Bytes, String> {
+    if let Some(path) = s.strip_prefix('@') {
+        return fs::read(path)
+            .map(Bytes::from)
+            .map_err(|e| format!("reading {}: {}", path, e));
+    }
+    let invalid = || format!("expected hex bytes or @<path>, got {:?}", s);
+    if !s.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+fn parse_sample_rate(s: &str) -> Result<f64, String> {
+    match s.parse() {
+        Ok(rate) if rate > 0.0 && rate <= 1.0 => Ok(rate),
+        _ => Err(format!("expected a fraction in (0, 1], got {:?}", s)),
+    }
+}
+
+fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match s {
+        "\\t" => Ok(b'\t'),
+        "\\0" => Ok(b'\0'),
+        _ if s.len() == 1 => Ok(s.as_bytes()[0]),
+        _ => Err(format!("delimiter must be a single byte, got {:?}", s)),
+    }
+}
+
+fn parse_override(s: &str) -> Result<(usize, String), String> {
+    let (index, cmd) = s
+        .split_once('=')
+        .ok_or_else(|| format!("mapper override must be <N>=<cmd>, got {:?}", s))?;
+    let index = index
+        .parse()
+        .map_err(|_| format!("mapper override index must be an integer, got {:?}", index))?;
+    Ok((index, cmd.to_owned()))
+}
+
+fn parse_infile_partition(s: &str) -> Result<(usize, PathBuf), String> {
+    let (index, path) = s
+        .split_once(':')
+        .ok_or_else(|| format!("infile partition must be <N>:<path>, got {:?}", s))?;
+    let index = index
+        .parse()
+        .map_err(|_| format!("infile partition index must be an integer, got {:?}", index))?;
+    Ok((index, PathBuf::from(path)))
+}
+
+fn parse_env(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!(
+            "environment variable must be <KEY>=<VALUE>, got {:?}",
+            s
+        )),
+    }
+}
+
+fn parse_byte_range(s: &str) -> Result<(usize, usize), String> {
+    let err = || {
+        format!(
+            "byte range must be <start>:<end> with start < end, got {:?}",
+            s
+        )
+    };
+    let (start, end) = s.split_once(':').ok_or_else(err)?;
+    match (start.parse(), end.parse()) {
+        (Ok(start), Ok(end)) if start < end => Ok((start, end)),
+        _ => Err(err()),
+    }
+}
+
+fn parse_field(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(0) | Err(_) => Err(format!("fields are positive integers, got {:?}", s)),
+        Ok(field) => Ok(field),
+    }
+}
+
+fn parse_jsonptr(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.starts_with('/') {
+        Ok(s.to_owned())
+    } else {
+        Err(format!(
+            "JSON pointer must be empty or start with '/', got {:?}",
+            s
+        ))
+    }
+}
+
+/// Reads the keys and folder indices of `--partition-map-file`.
+fn read_partition_map(path: &Path) -> Result<PartitionMap, String> {
+    let contents = fs::read(path).map_err(|e| format!("reading {}: {}", path.display(), e))?;
+    let mut partition_map = PartitionMap::new();
+    for (i, line) in contents.split(|&b| b == b'\n').enumerate() {
+        let fields: Vec<_> = line
+            .split(u8::is_ascii_whitespace)
+            .filter(|field| !field.is_empty())
+            .collect();
+        let (key, partition) = match fields[..] {
+            [] => continue,
+            [key, partition] => (key, partition),
+            _ => {
+                return Err(format!(
+                    "{}:{}: expected a key and a folder index",
+                    path.display(),
+                    i + 1
+                ))
+            }
+        };
+        let partition = String::from_utf8_lossy(partition)
+            .parse()
+            .map_err(|e| format!("{}:{}: bad folder index: {}", path.display(), i + 1, e))?;
+        partition_map.insert(key.to_vec(), partition);
+    }
+    Ok(partition_map)
+}
+
+/// Reads the sorted keys of `--split-points-file`.
+fn read_split_points(path: &Path) -> Result<Vec<Vec<u8>>, String> {
+    let contents = fs::read(path).map_err(|e| format!("reading {}: {}", path.display(), e))?;
+    let contents = contents.strip_suffix(b"\n").unwrap_or(&contents);
+    if contents.is_empty() {
+        return Ok(Vec::new());
+    }
+    let split_points: Vec<_> = contents
+        .split(|&b| b == b'\n')
+        .map(<[u8]>::to_vec)
+        .collect();
+    if let Some(i) = split_points.windows(2).position(|w| w[0] > w[1]) {
+        return Err(format!(
+            "split points in {} are not sorted: line {} comes after line {}",
+            path.display(),
+            i + 2,
+            i + 1
+        ));
+    }
+    Ok(split_points)
+}
+
+/// Expands glob patterns among `infiles` and walks `indirs`, returning
+/// the sorted, deduplicated list of input files.
+fn collect_infiles(infiles: &[PathBuf], indirs: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut paths = Vec::new();
+    for infile in infiles {
+        let pattern = match infile.to_str() {
+            Some(pattern) if pattern.contains(['*', '?', '[']) => pattern,
+            _ => {
+                paths.push(infile.clone());
+                continue;
+            }
+        };
+        let matches = glob::glob(pattern).map_err(|e| format!("bad glob {:?}: {}", pattern, e))?;
+        let nbefore = paths.len();
+        for path in matches {
+            paths.push(path.map_err(|e| format!("expanding {:?}: {}", pattern, e))?);
+        }
+        if paths.len() == nbefore {
+            return Err(format!("no files match {:?}", pattern));
+        }
+    }
+    for indir in indirs {
+        for entry in walkdir::WalkDir::new(indir) {
+            let entry = entry.map_err(|e| format!("walking {}: {}", indir.display(), e))?;
+            if entry.file_type().is_file() {
+                paths.push(entry.into_path());
+            }
+        }
+    }
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
+
+/// The bounds on the `--bufsize` picked by `--auto-bufsize`.
+const MIN_AUTO_BUFSIZE: usize = 64 * 1024;
+const MAX_AUTO_BUFSIZE: usize = 256 * 1024 * 1024;
+
+/// Returns the bytes of memory available for new allocations without
+/// swapping, the `MemAvailable` of `/proc/meminfo`.
+fn available_memory() -> io::Result<usize> {
+    let meminfo = fs::read_to_string("/proc/meminfo")?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|rest| rest.trim().strip_suffix("kB"))
+        .and_then(|kb| kb.trim().parse::<usize>().ok())
+        .map(|kb| kb * 1024)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "no MemAvailable in /proc/meminfo"))
+}
+
+/// Copies all of `input` into a temporary file, which is deleted on drop.
+fn buffer_input<R: Read>(mut input: R) -> NamedTempFile {
+    let mut file = NamedTempFile::new().expect("create input buffer file");
+    io::copy(&mut input, &mut file).expect("buffer input");
+    file.flush().expect("flush input buffer");
+    file
+}
+
+/// Reads all the lines of `chunks` into memory and writes them out in a
+/// uniformly random order to a temporary file, which is deleted on drop.
+/// A last line without a terminator gets one. With `mmap`, large chunks are
+/// read from memory maps.
+fn shuffle_input(
+    chunks: &[FileChunk],
+    terminator: u8,
+    seed: Option<u64>,
+    mmap: bool,
+) -> NamedTempFile {
+    let mut data = Vec::new();
+    for chunk in chunks {
+        chunk.dump(&mut data, mmap).unwrap_or_else(|e| {
+            eprintln!("error: reading {}: {}", chunk.path().display(), e);
+            process::exit(1)
+        });
+        if data.last().is_some_and(|&b| b != terminator) {
+            data.push(terminator);
+        }
+    }
+    let mut lines: Vec<&[u8]> = data.split_inclusive(|&b| b == terminator).collect();
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    lines.shuffle(&mut rng);
+    let mut file = BufWriter::new(NamedTempFile::new().expect("create shuffle buffer file"));
+    for line in lines {
+        file.write_all(line).expect("buffer shuffled input");
+    }
+    file.into_inner().expect("flush shuffle buffer")
+}
+
+/// Concatenates the lines of `chunks`, in order, into a temporary file,
+/// which is deleted on drop. A chunk's unterminated last line gets a
+/// terminator so it doesn't run into the next chunk's first.
+fn merge_chunks(chunks: &[FileChunk], terminator: u8) -> NamedTempFile {
+    let mut file = BufWriter::new(NamedTempFile::new().expect("create merged input file"));
+    for chunk in chunks {
+        let mut reader = chunk.reader();
+        let mut last = None;
+        loop {
+            let buf = reader.fill_buf().unwrap_or_else(|e| {
+                eprintln!("error: reading {}: {}", chunk.path().display(), e);
+                process::exit(1)
+            });
+            if buf.is_empty() {
+                break;
+            }
+            last = buf.last().copied();
+            file.write_all(buf).expect("merge input");
+            let nread = buf.len();
+            reader.consume(nread);
+        }
+        if last.is_some_and(|b| b != terminator) {
+            file.write_all(&[terminator]).expect("merge input");
+        }
+    }
+    file.into_inner().expect("flush merged input")
+}
+
+/// Copies lines from a child's stderr to ours, each behind `prefix`.
+fn relay_stderr(stderr: ChildStderr, prefix: String) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut stderr = BufReader::new(stderr);
+        let mut line = Vec::new();
+        while stderr
+            .read_until(b'\n', &mut line)
+            .expect("read child stderr")
+            > 0
+        {
+            if !line.ends_with(b"\n") {
+                line.push(b'\n');
+            }
+            let mut out = io::stderr().lock();
+            // nowhere left to report a failure to write to stderr
+            let _ = write!(out, "{} ", prefix).and_then(|_| out.write_all(&line));
+            line.clear();
+        }
+    })
+}
+
+/// Prints the `--dry-run` report of what would run to stdout.
+#[allow(clippy::too_many_arguments)]
+fn dry_run(
+    chunks: &[FileChunk],
+    chunk_partitions: &[Option<usize>],
+    mapper_cmds: &[&str],
+    folder_cmd: &str,
+    nthreads: usize,
+    nfolders: usize,
+    output_paths: &[PathBuf],
+    json: bool,
+) {
+    if json {
+        let mappers: Vec<_> = chunks
+            .iter()
+            .zip(mapper_cmds)
+            .zip(chunk_partitions)
+            .enumerate()
+            .map(|(i, ((chunk, cmd), partition))| {
+                let (start, stop) = chunk.range();
+                json!({
+                    "index": i,
+                    "command": cmd,
+                    "path": chunk.path().to_string_lossy(),
+                    "compression": chunk.compression().to_string(),
+                    "start": start,
+                    // unbounded compressed chunks read to EOF
+                    "stop": if stop == usize::MAX { None } else { Some(stop) },
+                    // sharded output has no single folder
+                    "folder": partition,
+                })
+            })
+            .collect();
+        let folders: Vec<_> = (0..nfolders)
+            .map(|i| {
+                json!({
+                    "index": i,
+                    "command": folder_cmd,
+                    // streamed to stdout without an output prefix
+                    "output": output_paths.get(i).map(|path| path.to_string_lossy()),
+                })
+            })
+            .collect();
+        let report = json!({
+            "chunks": chunks.len(),
+            "nthreads": nthreads,
+            "nfolders": nfolders,
+            "mappers": mappers,
+            "folders": folders,
+        });
+        println!("{}", report);
+        return;
+    }
+    println!("chunks: {}", chunks.len());
+    println!("nthreads: {}", nthreads);
+    println!("nfolders: {}", nfolders);
+    for (i, ((chunk, cmd), partition)) in chunks
+        .iter()
+        .zip(mapper_cmds)
+        .zip(chunk_partitions)
+        .enumerate()
+    {
+        let (start, stop) = chunk.range();
+        let stop = if stop == usize::MAX {
+            "EOF".to_owned()
+        } else {
+            stop.to_string()
+        };
+        let routing = match partition {
+            Some(partition) => format!(" -> folder {}", partition),
+            None => String::new(),
+        };
+        println!(
+            "mapper {}: {} < {} [{}, {}) ({}){}",
+            i,
+            cmd,
+            chunk.path().display(),
+            start,
+            stop,
+            chunk.compression(),
+            routing
+        );
+    }
+    for i in 0..nfolders {
+        match output_paths.get(i) {
+            Some(path) => println!("folder {}: {} > {}", i, folder_cmd, path.display()),
+            None => println!("folder {}: {} > stdout", i, folder_cmd),
+        }
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// Returns the temporary path `--atomic` output is written to.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Returns the `--output-per-key` path of the file for `key`.
+fn key_file_path(outprefix: &Path, key: &[u8]) -> PathBuf {
+    let mut escaped = String::new();
+    for &b in key {
+        if b.is_ascii_alphanumeric() || b"-._".contains(&b) {
+            escaped.push(b as char);
+        } else {
+            escaped.push_str(&format!("%{:02X}", b));
+        }
+    }
+    let mut name = outprefix.as_os_str().to_owned();
+    name.push(format!("_{}.txt", escaped));
+    PathBuf::from(name)
+}
+
+/// Deletes files, complaining about any that exist but can't be removed.
+fn remove_files(paths: &[PathBuf]) {
+    for path in paths {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                eprintln!("error removing {}: {}", path.display(), e)
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Deletes partial output after an interrupt and exits accordingly.
+fn interrupted_exit(cleanup_paths: &[PathBuf]) -> ! {
+    remove_files(cleanup_paths);
+    eprintln!("interrupted, removed partial output");
+    process::exit(130)
+}
+
+/// Returns what to feed a mapper over a pipe: a decompressing reader, or
+/// with `--mmap`, a memory map of an uncompressed chunk.
+#[cfg_attr(not(feature = "mmap"), allow(unused_variables))]
+fn piped_input(chunk: &FileChunk, mmap: bool) -> Box<dyn Read + Send> {
+    #[cfg(feature = "mmap")]
+    {
+        // an empty chunk has nothing to map, and mapping nothing isn't
+        // portable
+        let mappable =
+            chunk.compression() == Compression::None && !chunk.is_stream() && chunk.nbytes() > 0;
+        if mmap && mappable {
+            return Box::new(io::Cursor::new(chunk.mmap_bytes()));
+        }
+    }
+    Box::new(chunk.reader())
+}
+
+/// How lines are routed to folders, see `--shard-mode`; hashing is
+/// further picked by `--hasher` and `--consistent-hash`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Routing {
+    Hash,
+    RoundRobin,
+}
+
+impl FromStr for Routing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hash" => Ok(Routing::Hash),
+            "roundrobin" => Ok(Routing::RoundRobin),
+            _ => Err(format!("unknown shard mode {:?}", s)),
+        }
+    }
+}
+
+/// What to do about a failed mapper, see `--on-mapper-failure`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OnMapperFailure {
+    Abort,
+    Skip,
+    Retry,
+}
+
+impl FromStr for OnMapperFailure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(OnMapperFailure::Abort),
+            "skip" => Ok(OnMapperFailure::Skip),
+            "retry" => Ok(OnMapperFailure::Retry),
+            _ => Err(format!("unknown mapper failure policy {:?}", s)),
+        }
+    }
+}
+
+/// How folder output is written out, see `--output-format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Plain,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(OutputFormat::Plain),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unknown output format {:?}", s)),
+        }
+    }
+}
+
+/// The default `--max-output-files`.
+const DEFAULT_MAX_OUTPUT_FILES: usize = 1000;
+
+/// How long `--on-mapper-failure retry` waits before its first retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How child processes are run, see `--shell`, `--env` and `--clear-env`,
+/// `--max-mapper-memory` or `--max-folder-memory`, and `--cpu-affinity`.
+#[derive(Clone, Debug)]
+struct ChildEnv {
+    shell: PathBuf,
+    vars: Vec<(String, String)>,
+    clear: bool,
+    memory_limit: Option<usize>,
+    /// The CPUs to run on, or any if empty.
+    cpus: Vec<usize>,
+}
+
+impl ChildEnv {
+    /// Returns a shell command in this environment, ready for its `-c`
+    /// command line.
+    fn shell(&self) -> Command {
+        let mut cmd = Command::new(&self.shell);
+        if self.clear {
+            cmd.env_clear();
+        }
+        cmd.envs(self.vars.iter().map(|(key, value)| (key, value)));
+        if let Some(limit) = self.memory_limit {
+            let limit = libc::rlimit {
+                rlim_cur: limit as libc::rlim_t,
+                rlim_max: limit as libc::rlim_t,
+            };
+            // setrlimit is async-signal-safe, so it may run between fork
+            // and exec
+            unsafe {
+                cmd.pre_exec(move || match libc::setrlimit(libc::RLIMIT_AS, &limit) {
+                    0 => Ok(()),
+                    _ => Err(io::Error::last_os_error()),
+                });
+            }
+        }
+        if !self.cpus.is_empty() {
+            affinity::pin(&mut cmd, &self.cpus);
+        }
+        cmd
+    }
+}
+
+/// Describes how a child process failed, calling out a SIGKILL, whether of
+/// the process or of the command its shell ran, as likely being over its
+/// `memory_limit`, if it has one.
+fn describe_exit(status: ExitStatus, memory_limit: Option<usize>) -> String {
+    let killed =
+        status.signal() == Some(libc::SIGKILL) || status.code() == Some(128 + libc::SIGKILL);
+    match memory_limit {
+        Some(limit) if killed => {
+            format!(
+                "{}, likely over its memory limit of {} bytes",
+                status, limit
+            )
+        }
+        _ => status.to_string(),
+    }
+}
+
+/// Runs a buffer of mapper output through the `--combiner`, returning its
+/// output in place of the buffer, with a trailing terminator added if
+/// missing, so it can't run into the next buffer sent to the folder.
+fn combine(cmd: &str, env: &ChildEnv, buf: Bytes, terminator: u8) -> Result<Bytes, String> {
+    let mut child = env
+        .shell()
+        .arg("-c")
+        .arg(cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("spawn: {}", e))?;
+    let mut stdin = child.stdin.take().expect("combiner stdin");
+    // write from another thread so a combiner streaming its output can't
+    // deadlock on a full pipe
+    let writer = thread::spawn(move || match stdin.write_all(&buf) {
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => Err(e),
+        _ => Ok(()),
+    });
+    let mut output = Vec::new();
+    let read_result = child
+        .stdout
+        .take()
+        .expect("combiner stdout")
+        .read_to_end(&mut output);
+    let write_result = writer.join().expect("combiner writer join");
+    let status = child.wait().map_err(|e| format!("wait: {}", e))?;
+    if !status.success() {
+        return Err(describe_exit(status, env.memory_limit));
+    }
+    read_result.map_err(|e| format!("reading output: {}", e))?;
+    write_result.map_err(|e| format!("writing input: {}", e))?;
+    if output.last().is_some_and(|&b| b != terminator) {
+        output.push(terminator);
+    }
+    Ok(Bytes::from(output))
+}
+
+/// Returns a `--presort` command sorting lines by the key before the first
+/// `delimiter`, bytewise, keeping lines with the same key in order.
+fn presort_command(delimiter: u8, null: bool) -> Command {
+    let mut cmd = Command::new("sort");
+    cmd.env("LC_ALL", "C").arg("--stable").arg("--key=1,1");
+    // argv can't hold a null byte, but GNU sort takes this escape
+    match delimiter {
+        b'\0' => cmd.arg("--field-separator=\\0"),
+        _ => cmd.arg(OsStr::from_bytes(
+            &[&b"--field-separator="[..], &[delimiter]].concat(),
+        )),
+    };
+    if null {
+        cmd.arg("--zero-terminated");
+    }
+    cmd
+}
+
+/// A folder, and what it's composed with, as spawned.
+#[derive(Default)]
+struct FolderHandles {
+    // none for a socket folder or key files, which write into `sink`
+    child: Option<Child>,
+    sink: Option<Box<dyn Write + Send>>,
+    presort: Option<Child>,
+    output_writer: Option<JoinHandle<io::Result<WrittenOutput>>>,
+    watchdog: Option<Watchdog>,
+    // where a respawned folder writes
+    respawn_output: Option<File>,
+    post: Option<Child>,
+}
+
+/// How a folder, and what it's composed with, fared.
+struct FolderResult {
+    write_result: io::Result<()>,
+    // a socket folder's keeps running
+    status: Option<ExitStatus>,
+    timed_out: bool,
+    presort_status: Option<ExitStatus>,
+    post_status: Option<ExitStatus>,
+    output_result: io::Result<WrittenOutput>,
+}
+
+/// What became of a folder's output, if it's written by us rather than
+/// straight into its file.
+#[derive(Default)]
+struct WrittenOutput {
+    checksum: Option<String>,
+    // files moved on to past `--max-output-bytes` or `--max-output-lines`
+    rotated_paths: Vec<PathBuf>,
+}
+
+/// Checks that every line of each output file belongs in its folder, see
+/// `--verify`, returning how many don't.
+fn verify_outputs(
+    paths: &[PathBuf],
+    key: &Key,
+    mode: ShardMode,
+    split_points: Option<&[Vec<u8>]>,
+    partition_map: Option<&PartitionMap>,
+    terminator: u8,
+) -> usize {
+    thread::scope(|scope| {
+        let threads: Vec<_> = paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| {
+                scope.spawn(move || {
+                    let file = File::open(path).expect("open output");
+                    let mut misrouted = 0;
+                    let mut lineno = 0;
+                    BufReader::new(file)
+                        .for_byte_record_with_terminator(terminator, |line| {
+                            lineno += 1;
+                            let expected = sharder::partition_of(
+                                line,
+                                terminator,
+                                paths.len(),
+                                key,
+                                mode,
+                                split_points,
+                                partition_map,
+                            )
+                            .expect("hash mode");
+                            if expected != i {
+                                misrouted += 1;
+                                eprintln!(
+                                    "error: {}:{}: belongs in folder {}, not {}: {}",
+                                    path.display(),
+                                    lineno,
+                                    expected,
+                                    i,
+                                    String::from_utf8_lossy(line).trim_end()
+                                );
+                            }
+                            Ok(true)
+                        })
+                        .expect("read output");
+                    misrouted
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|handle| handle.join().expect("verify join"))
+            .sum()
+    })
+}
+
+/// Copies a folder's stdout into `writer`, compressed if asked, returning
+/// the writer once done.
+fn compress_output<R: Read, W: Write>(
+    mut output: R,
+    mut writer: W,
+    compression: Option<(Compression, u32)>,
+) -> io::Result<W> {
+    match compression {
+        None | Some((Compression::None, _)) => {
+            io::copy(&mut output, &mut writer)?;
+            Ok(writer)
+        }
+        Some((Compression::Gzip, level)) => {
+            let mut encoder = GzEncoder::new(writer, flate2::Compression::new(level));
+            io::copy(&mut output, &mut encoder)?;
+            encoder.finish()
+        }
+        Some((Compression::Zstd, level)) => {
+            let mut encoder = zstd::Encoder::new(writer, level as i32)?;
+            io::copy(&mut output, &mut encoder)?;
+            encoder.finish()
+        }
+    }
+}
+
+/// Copies a folder's stdout, after the `header`, into its output file,
+/// along with the file's checksum if asked.
+fn write_output<R: Read>(
+    output: R,
+    header: Bytes,
+    file: File,
+    compression: Option<(Compression, u32)>,
+    checksum: Option<checksum::Algorithm>,
+) -> io::Result<WrittenOutput> {
+    let output = io::Cursor::new(header).chain(output);
+    let checksum = match checksum {
+        Some(algorithm) => {
+            let writer = ChecksumWriter::new(file, algorithm);
+            Some(compress_output(output, writer, compression)?.finish()?)
+        }
+        None => {
+            compress_output(output, file, compression)?;
+            None
+        }
+    };
+    Ok(WrittenOutput {
+        checksum,
+        ..WrittenOutput::default()
+    })
+}
+
+/// Copies a folder's stdout into its output files, split up by `writer`.
+fn split_output<R: Read>(mut output: R, mut writer: CountingWriter) -> io::Result<WrittenOutput> {
+    io::copy(&mut output, &mut writer)?;
+    Ok(WrittenOutput {
+        rotated_paths: writer.finish()?,
+        ..WrittenOutput::default()
+    })
+}
+
+/// A folder's output with each line wrapped in a JSON object along with
+/// the folder's index, for `--output-format json`.
+struct JsonLines<R> {
+    inner: R,
+    partition: usize,
+    terminator: u8,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl<R> JsonLines<R> {
+    fn new(inner: R, partition: usize, terminator: u8) -> Self {
+        Self {
+            inner,
+            partition,
+            terminator,
+            block: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Read for JsonLines<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut line = Vec::new();
+        if self.pos == self.block.len() {
+            self.block.clear();
+            self.pos = 0;
+            while self.block.len() < FEED_BLOCK_SIZE {
+                line.clear();
+                if self.inner.read_until(self.terminator, &mut line)? == 0 {
+                    break;
+                }
+                let content = line.strip_suffix(&[self.terminator]).unwrap_or(&line);
+                write!(self.block, "{{\"partition\":{},\"line\":", self.partition)?;
+                serde_json::to_writer(&mut self.block, &String::from_utf8_lossy(content))?;
+                self.block.push(b'}');
+                self.block.push(self.terminator);
+            }
+        }
+        let nread = (self.block.len() - self.pos).min(buf.len());
+        buf[..nread].copy_from_slice(&self.block[self.pos..self.pos + nread]);
+        self.pos += nread;
+        Ok(nread)
+    }
+}
+
+/// Writes all of a (decompressed) chunk into a mapper's stdin.
+fn feed<R: Read>(mut reader: R, mut stdin: ChildStdin) {
+    match io::copy(&mut reader, &mut stdin) {
+        // like `head -c`, don't mind the mapper hanging up early
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => panic!("feed mapper: {}", e),
+        _ => (),
+    }
+}
+
+/// How many bytes of lines go to one of a mapper's workers before the
+/// next, and how much of a worker's output is read at a time.
+const FEED_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Like [`feed`], but only writes the lines for which `keep` holds,
+/// given each line without its terminator, dealing them out to the
+/// `stdins` of a mapper's workers in blocks of [`FEED_BLOCK_SIZE`] bytes.
+fn feed_lines<R: Read, F: FnMut(&[u8]) -> bool>(
+    reader: R,
+    stdins: Vec<ChildStdin>,
+    terminator: u8,
+    mut keep: F,
+) {
+    let mut stdins: Vec<_> = stdins.into_iter().map(io::BufWriter::new).collect();
+    let mut current = 0;
+    let mut block = 0;
+    let result = BufReader::new(reader)
+        .for_byte_record_with_terminator(terminator, |line| {
+            if keep(line.strip_suffix(&[terminator]).unwrap_or(line)) {
+                stdins[current].write_all(line)?;
+                block += line.len();
+                if block >= FEED_BLOCK_SIZE {
+                    block = 0;
+                    current = (current + 1) % stdins.len();
+                }
+            }
+            Ok(true)
+        })
+        .and_then(|_| stdins.iter_mut().try_for_each(Write::flush));
+    match result {
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => panic!("feed mapper: {}", e),
+        _ => (),
+    }
+}
+
+/// Runs the lines of `reader` for which `keep` holds, given each line
+/// without its terminator, through a new mapper every `batch_size` lines,
+/// see `--batch-size`, sending along each batch's output as it's done.
+fn map_batches<R: Read, F: FnMut(&[u8]) -> bool>(
+    reader: R,
+    cmd: &str,
+    env: &ChildEnv,
+    batch_size: usize,
+    terminator: u8,
+    mut keep: F,
+    tx: SyncSender<Vec<u8>>,
+) -> Result<(), String> {
+    // a failed send means sharding stopped, and output is just dropped
+    let run = |batch: Vec<u8>| -> Result<bool, String> {
+        let output = combine(cmd, env, Bytes::from(batch), terminator)?;
+        Ok(tx.send(output.into()).is_ok())
+    };
+    let mut batch = Vec::new();
+    let mut nlines = 0;
+    let mut result = Ok(true);
+    BufReader::new(reader)
+        .for_byte_record_with_terminator(terminator, |line| {
+            if !keep(line.strip_suffix(&[terminator]).unwrap_or(line)) {
+                return Ok(true);
+            }
+            batch.extend_from_slice(line);
+            if line.last() != Some(&terminator) {
+                batch.push(terminator);
+            }
+            nlines += 1;
+            if nlines == batch_size {
+                nlines = 0;
+                result = run(mem::take(&mut batch));
+            }
+            Ok(result == Ok(true))
+        })
+        .map_err(|e| format!("reading input: {}", e))?;
+    if result? && nlines > 0 {
+        run(batch)?;
+    }
+    Ok(())
+}
+
+/// Runs mapper `i` over `chunk` with its output spooled to a temporary
+/// file, which is only sent along once the mapper succeeds, so that a
+/// failed run can be retried, once for each of `keeps` after the first,
+/// see `--on-mapper-failure`. Each run drops the lines `keeps` say to, if
+/// set, like [`feed_lines`].
+///
+/// Returns why the last run failed, if they all did.
+#[allow(clippy::too_many_arguments)]
+fn map_spooled<F: FnMut(&[u8]) -> bool>(
+    i: usize,
+    chunk: FileChunk,
+    cmd: &str,
+    env: &ChildEnv,
+    terminator: u8,
+    mmap: bool,
+    keeps: Vec<Option<F>>,
+    tx: SyncSender<File>,
+) -> Result<(), String> {
+    let nattempts = keeps.len();
+    let mut backoff = RETRY_BACKOFF;
+    for (attempt, keep) in keeps.into_iter().enumerate() {
+        let mut spool = tempfile::tempfile().map_err(|e| format!("spooling output: {}", e))?;
+        let stdout = spool
+            .try_clone()
+            .map_err(|e| format!("spooling output: {}", e))?;
+        let mut child = env
+            .shell()
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::piped())
+            .stdout(stdout)
+            .spawn()
+            .map_err(|e| format!("spawn: {}", e))?;
+        let stdin = child.stdin.take().expect("mapper stdin");
+        // the chunk is read afresh every time
+        let input = piped_input(&chunk, mmap);
+        match keep {
+            Some(keep) => feed_lines(input, vec![stdin], terminator, keep),
+            None => feed(input, stdin),
+        }
+        let status = child.wait().map_err(|e| format!("wait: {}", e))?;
+        if status.success() {
+            spool
+                .seek(SeekFrom::Start(0))
+                .map_err(|e| format!("spooling output: {}", e))?;
+            // a failed send means sharding stopped, and output is just dropped
+            let _ = tx.send(spool);
+            return Ok(());
+        }
+        if attempt + 1 == nattempts {
+            return Err(describe_exit(status, env.memory_limit));
+        }
+        eprintln!(
+            "warning: mapper {} failed: {}, retrying in {:?} (retry {} of {})",
+            i,
+            describe_exit(status, env.memory_limit),
+            backoff,
+            attempt + 1,
+            nattempts - 1
+        );
+        thread::sleep(backoff);
+        backoff *= 2;
+    }
+    unreachable!("a mapper runs at least once")
+}
+
+/// The output of a mapper spooled by [`map_spooled`], empty if it never
+/// succeeded.
+struct SpooledOutput {
+    spool: Receiver<File>,
+    file: Option<File>,
+}
+
+impl Read for SpooledOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.file.is_none() {
+            match self.spool.recv() {
+                Ok(file) => self.file = Some(file),
+                Err(_) => return Ok(0),
+            }
+        }
+        self.file.as_mut().expect("spool").read(buf)
+    }
+}
+
+/// Mapper output without the lines whose key is blank, for
+/// `--skip-empty-keys`, which are counted and written to any discard file
+/// instead.
+struct BlankKeyFilter<R> {
+    inner: R,
+    key: Key,
+    terminator: u8,
+    discard: Option<Arc<Mutex<File>>>,
+    discarded: Arc<AtomicUsize>,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl<R> BlankKeyFilter<R> {
+    fn new(
+        inner: R,
+        key: Key,
+        terminator: u8,
+        discard: Option<Arc<Mutex<File>>>,
+        discarded: Arc<AtomicUsize>,
+    ) -> Self {
+        Self {
+            inner,
+            key,
+            terminator,
+            discard,
+            discarded,
+            block: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Read for BlankKeyFilter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let nread = available.len().min(buf.len());
+        buf[..nread].copy_from_slice(&available[..nread]);
+        self.consume(nread);
+        Ok(nread)
+    }
+}
+
+impl<R: BufRead> BufRead for BlankKeyFilter<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let mut line = Vec::new();
+        let mut discarded = Vec::new();
+        while self.pos == self.block.len() {
+            self.block.clear();
+            self.pos = 0;
+            let mut eof = false;
+            while self.block.len() < FEED_BLOCK_SIZE {
+                line.clear();
+                if self.inner.read_until(self.terminator, &mut line)? == 0 {
+                    eof = true;
+                    break;
+                }
+                let blank = self
+                    .key
+                    .extract(&line, self.terminator)
+                    .is_some_and(|key| key.iter().all(u8::is_ascii_whitespace));
+                if blank {
+                    self.discarded.fetch_add(1, Ordering::Relaxed);
+                    discarded.extend_from_slice(&line);
+                } else {
+                    self.block.extend_from_slice(&line);
+                }
+            }
+            if let Some(discard) = self.discard.as_ref().filter(|_| !discarded.is_empty()) {
+                discard.lock().unwrap().write_all(&discarded)?;
+                discarded.clear();
+            }
+            if eof {
+                break;
+            }
+        }
+        Ok(&self.block[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+/// A writer to each `--folder` of a partition at once, which fails as soon
+/// as any of them does.
+struct FanOut(Vec<Box<dyn Write + Send>>);
+
+impl Write for FanOut {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for writer in &mut self.0 {
+            writer.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.iter_mut().try_for_each(Write::flush)
+    }
+}
+
+/// The output of a mapper's workers, interleaved a block of whole lines
+/// at a time, see [`merge_outputs`].
+struct MergedOutput {
+    blocks: Receiver<Vec<u8>>,
+    block: io::Cursor<Vec<u8>>,
+}
+
+impl Read for MergedOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let nread = self.block.read(buf)?;
+            if nread > 0 || buf.is_empty() {
+                return Ok(nread);
+            }
+            match self.blocks.recv() {
+                Ok(block) => self.block = io::Cursor::new(block),
+                // every worker is done
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Merges the outputs of a mapper's workers into one stream, with a
+/// thread per worker forwarding its lines as they're written. A final
+/// unterminated line gets a terminator so it doesn't run into another
+/// worker's output.
+fn merge_outputs(
+    outputs: Vec<ChildStdout>,
+    terminator: u8,
+) -> (MergedOutput, Vec<thread::JoinHandle<()>>) {
+    let (tx, rx) = sync_channel(outputs.len());
+    let threads = outputs
+        .into_iter()
+        .map(|mut output| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let mut buf = Vec::new();
+                loop {
+                    let start = buf.len();
+                    buf.resize(start + FEED_BLOCK_SIZE, 0);
+                    let nread = output.read(&mut buf[start..]).expect("read mapper output");
+                    buf.truncate(start + nread);
+                    if nread == 0 {
+                        if !buf.is_empty() {
+                            buf.push(terminator);
+                            // the receiver only hangs up once it's done
+                            let _ = tx.send(buf);
+                        }
+                        return;
+                    }
+                    if let Some(end) = buf[start..].iter().rposition(|&b| b == terminator) {
+                        let rest = buf.split_off(start + end + 1);
+                        if tx.send(std::mem::replace(&mut buf, rest)).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    let merged = MergedOutput {
+        blocks: rx,
+        block: io::Cursor::new(Vec::new()),
+    };
+    (merged, threads)
+}
+
+/// Mappers as spawned by [`spawn_mappers`].
+struct Mappers {
+    // the processes of each chunk's mapper, one per worker, if it runs as
+    // processes of its own
+    processes: Vec<Vec<Child>>,
+    // feeders of mapper stdins, for input read in-process
+    feeders: Vec<JoinHandle<()>>,
+    // with the index of the mapper of each process
+    watchdogs: Vec<(usize, Option<Watchdog>)>,
+    cursors: Vec<(PathBuf, ChunkCursor)>,
+    // mappers run from a thread, see `--batch-size` and
+    // `--on-mapper-failure`, and their outputs
+    threads: Vec<JoinHandle<Result<(), String>>>,
+    outputs: Vec<Box<dyn Read + Send>>,
+}
+
+/// Spawns a mapper for each of `chunks`, running `mapper_cmds[i]` on chunk
+/// `i`, either directly on its file, fed by a thread, per batch, or spooled
+/// to be retried.
+#[allow(clippy::too_many_arguments)]
+fn spawn_mappers(
+    opt: &Opt,
+    chunks: &[FileChunk],
+    mapper_cmds: &[&str],
+    child_env: &ChildEnv,
+    cpu_sets: &[Vec<usize>],
+    mmap: bool,
+    terminator: u8,
+    shutdown: &Arc<AtomicBool>,
+    stderr_relays: &mut Vec<JoinHandle<()>>,
+) -> Mappers {
+    let mapper_timeout = opt.mapper_timeout.map(Duration::from_secs_f64);
+    let line_filter = opt.input_line_filter.clone().map(Arc::new);
+    let spooled = opt.on_mapper_failure != OnMapperFailure::Abort;
+    let mut mapper_feeders = Vec::new();
+    let mut mapper_watchdogs = Vec::new();
+    let mut cursors = Vec::new();
+    let workers = opt.mapper_workers;
+    // mappers run from a thread of their own, rather than as a process
+    // per worker, with their outputs and any reason they failed
+    let mut mapper_threads = Vec::new();
+    let mut threaded_outputs = Vec::new();
+    let keeper = |i: usize| {
+        let line_filter = line_filter.clone();
+        let invert = opt.input_line_filter_invert;
+        let mut sample = opt.sample_rate.map(|rate| {
+            let rng = match opt.seed {
+                Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(i as u64)),
+                None => StdRng::from_entropy(),
+            };
+            (rate, rng)
+        });
+        move |line: &[u8]| {
+            if line_filter
+                .as_ref()
+                .is_some_and(|f| f.is_match(line) != invert)
+            {
+                return false;
+            }
+            match &mut sample {
+                Some((rate, rng)) => rng.gen::<f64>() < *rate,
+                None => true,
+            }
+        }
+    };
+    let processes = chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mapper_cmd = mapper_cmds[i];
+            let mut child_env = child_env.clone();
+            child_env.memory_limit = opt.max_mapper_memory;
+            child_env.cpus = pinned(cpu_sets, i);
+            if opt.mapper_env_per_chunk {
+                let (start, stop) = chunk.range();
+                let stop = if stop == usize::MAX {
+                    String::new()
+                } else {
+                    stop.to_string()
+                };
+                child_env.vars.extend([
+                    ("SLB_CHUNK_INDEX".to_owned(), i.to_string()),
+                    ("SLB_CHUNK_START".to_owned(), start.to_string()),
+                    ("SLB_CHUNK_STOP".to_owned(), stop),
+                    ("SLB_NCHUNKS".to_owned(), chunks.len().to_string()),
+                ]);
+            }
+            if let Some(batch_size) = opt.batch_size {
+                // mappers are only started as batches fill up
+                let (tx, rx) = sync_channel(1);
+                let input = piped_input(chunk, mmap);
+                let cmd = mapper_cmd.to_owned();
+                let env = child_env.clone();
+                let keep = keeper(i);
+                mapper_threads.push(thread::spawn(move || {
+                    map_batches(input, &cmd, &env, batch_size, terminator, keep, tx)
+                }));
+                threaded_outputs.push(Box::new(MergedOutput {
+                    blocks: rx,
+                    block: io::Cursor::new(Vec::new()),
+                }) as Box<dyn Read + Send>);
+                return Vec::new();
+            }
+            let filtered = line_filter.is_some() || opt.sample_rate.is_some();
+            if spooled {
+                let (tx, rx) = sync_channel(1);
+                let nattempts = match opt.on_mapper_failure {
+                    OnMapperFailure::Retry if !chunk.is_stream() => opt.mapper_retry_count + 1,
+                    _ => 1,
+                };
+                let keeps: Vec<_> = (0..nattempts)
+                    .map(|_| filtered.then(|| keeper(i)))
+                    .collect();
+                let chunk = chunk.clone();
+                let cmd = mapper_cmd.to_owned();
+                let env = child_env.clone();
+                mapper_threads.push(thread::spawn(move || {
+                    map_spooled(i, chunk, &cmd, &env, terminator, mmap, keeps, tx)
+                }));
+                threaded_outputs.push(Box::new(SpooledOutput {
+                    spool: rx,
+                    file: None,
+                }) as Box<dyn Read + Send>);
+                return Vec::new();
+            }
+            let direct =
+                chunk.compression() == Compression::None && !mmap && !filtered && workers == 1;
+            let mut children: Vec<_> = (0..workers)
+                .map(|w| {
+                    let mut cmd = child_env.shell();
+                    cmd.arg("-c").stdout(Stdio::piped());
+                    if direct && chunk.is_stream() {
+                        // read until EOF, which can't be tracked
+                        cmd.arg(mapper_cmd).stdin(chunk.file());
+                    } else if direct {
+                        let file = chunk.file();
+                        if opt.progress || opt.progress_bar {
+                            let cursor = ChunkCursor::new(&file, chunk.nbytes());
+                            cursors.push((chunk.path().to_owned(), cursor));
+                        }
+                        cmd.arg(format!(
+                            "head -c {} | {} -c '{}'",
+                            chunk.nbytes(),
+                            child_env.shell.display(),
+                            mapper_cmd
+                        ))
+                        .stdin(file);
+                    } else {
+                        // decompress, mmap, filter or deal out to workers
+                        // in-process, feeding the mapper over a pipe
+                        cmd.arg(mapper_cmd).stdin(Stdio::piped());
+                    }
+                    if opt.capture_child_stderr {
+                        cmd.stderr(Stdio::piped());
+                    }
+                    if mapper_timeout.is_some() {
+                        cmd.process_group(0);
+                    }
+                    let mut child = cmd
+                        .spawn()
+                        .unwrap_or_else(|err| panic!("error spawn map child {}: {}", i, err));
+                    mapper_watchdogs.push((
+                        i,
+                        mapper_timeout.map(|timeout| {
+                            Watchdog::spawn(child.id(), timeout, Arc::clone(shutdown))
+                        }),
+                    ));
+                    if let Some(stderr) = child.stderr.take() {
+                        let prefix = if workers > 1 {
+                            format!("[mapper-{}.{}]", i, w)
+                        } else {
+                            format!("[mapper-{}]", i)
+                        };
+                        stderr_relays.push(relay_stderr(stderr, prefix));
+                    }
+                    child
+                })
+                .collect();
+            if !direct {
+                let mut stdins: Vec<_> = children
+                    .iter_mut()
+                    .map(|child| child.stdin.take().expect("mapper stdin"))
+                    .collect();
+                let input = piped_input(chunk, mmap);
+                if filtered || workers > 1 {
+                    let keep = keeper(i);
+                    mapper_feeders.push(thread::spawn(move || {
+                        feed_lines(input, stdins, terminator, keep)
+                    }));
+                } else {
+                    let stdin = stdins.pop().expect("mapper stdin");
+                    mapper_feeders.push(thread::spawn(move || feed(input, stdin)));
+                }
+            }
+            children
+        })
+        .collect();
+    Mappers {
+        processes,
+        feeders: mapper_feeders,
+        watchdogs: mapper_watchdogs,
+        cursors,
+        threads: mapper_threads,
+        outputs: threaded_outputs,
+    }
+}
+
+/// What every folder is set up with, see [`FolderSetup::spawn`].
+struct FolderSetup<'a> {
+    opt: &'a Opt,
+    folder_cmd: &'a str,
+    child_env: &'a ChildEnv,
+    cpu_sets: &'a [Vec<usize>],
+    nfolders: usize,
+    key: &'a Key,
+    terminator: u8,
+    delimiter: u8,
+    null: bool,
+    output_paths: &'a [PathBuf],
+    write_paths: &'a [PathBuf],
+    // those of every --folder after the first, by partition
+    fanout_paths: &'a [Vec<PathBuf>],
+    fanout_write_paths: &'a [Vec<PathBuf>],
+    output_header: &'a Bytes,
+    merged: Option<&'a File>,
+    output_compression: Option<(Compression, u32)>,
+    output_limit: Option<OutputLimit>,
+    // whether folders may be replaced, see `--hot-reload`
+    reloading: bool,
+    folder_timeout: Option<Duration>,
+    shutdown: &'a Arc<AtomicBool>,
+    // created as keys turn up, see --output-per-key
+    key_file_paths: &'a Arc<Mutex<Vec<PathBuf>>>,
+}
+
+impl FolderSetup<'_> {
+    /// The environment of folder `i`, writing to `output`.
+    fn env(&self, i: usize, output: Option<&PathBuf>) -> ChildEnv {
+        let mut child_env = self.child_env.clone();
+        child_env.memory_limit = self.opt.max_folder_memory;
+        child_env.cpus = pinned(self.cpu_sets, i);
+        if self.opt.folder_env_per_partition {
+            let output = output.map_or_else(String::new, |path| {
+                let path = std::path::absolute(path).unwrap_or_else(|_| path.clone());
+                path.display().to_string()
+            });
+            child_env.vars.extend([
+                ("SLB_PARTITION_INDEX".to_owned(), i.to_string()),
+                ("SLB_NPARTITIONS".to_owned(), self.nfolders.to_string()),
+                ("SLB_OUTPUT_PATH".to_owned(), output),
+            ]);
+        }
+        child_env
+    }
+
+    /// Spawns folder `i`, or connects to it with `--socket-folder`, and
+    /// whatever it's composed with: a presort, an output pipe and a thread
+    /// writing its output. Merged output is spooled to the end of
+    /// `spools`, and with `--mapper-output-passthrough` the folder reads
+    /// the next of `passthrough_outputs`.
+    fn spawn(
+        &self,
+        i: usize,
+        spools: &mut Vec<File>,
+        passthrough_outputs: &mut impl Iterator<Item = ChildStdout>,
+        stderr_relays: &mut Vec<JoinHandle<()>>,
+    ) -> FolderHandles {
+        let FolderSetup {
+            opt,
+            folder_cmd,
+            key,
+            terminator,
+            delimiter,
+            null,
+            output_paths,
+            write_paths,
+            output_header,
+            merged,
+            output_compression,
+            output_limit,
+            reloading,
+            folder_timeout,
+            shutdown,
+            key_file_paths,
+            ..
+        } = *self;
+        let child_env = self.env(i, output_paths.get(i));
+        if opt.socket_folder {
+            let mut path = output_paths[i].clone().into_os_string();
+            path.push(".sock");
+            let path = PathBuf::from(path);
+            let stream = socket::connect(&path, &output_paths[i], folder_cmd, &child_env)
+                .unwrap_or_else(|e| {
+                    eprintln!("error: connecting to folder at {}: {}", path.display(), e);
+                    process::exit(1)
+                });
+            return FolderHandles {
+                sink: Some(Box::new(stream)),
+                ..FolderHandles::default()
+            };
+        }
+        if opt.output_per_key {
+            let outprefix = opt.outprefix.clone().expect("required by clap");
+            let paths = Arc::clone(key_file_paths);
+            let files = sharder::KeyFiles::new(
+                key.clone(),
+                terminator,
+                opt.max_output_files.unwrap_or(DEFAULT_MAX_OUTPUT_FILES),
+                move |key: &[u8]| {
+                    let path = key_file_path(&outprefix, key);
+                    paths.lock().unwrap().push(path.clone());
+                    path
+                },
+            );
+            return FolderHandles {
+                sink: Some(Box::new(files)),
+                ..FolderHandles::default()
+            };
+        }
+        let file = match &merged {
+            Some(merged) if i == 0 => Some(merged.try_clone().expect("duplicate merged output")),
+            Some(_) => {
+                let spool = tempfile::tempfile().expect("create output spool");
+                spools.push(spool.try_clone().expect("duplicate output spool"));
+                Some(spool)
+            }
+            None => write_paths.get(i).map(|path| {
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .append(opt.append)
+                    .truncate(!opt.append)
+                    .open(path)
+                    .expect("write file")
+            }),
+        };
+        // merged output only starts with the header once
+        let header = match &merged {
+            Some(_) if i > 0 => Bytes::new(),
+            _ => output_header.clone(),
+        };
+        // a reloaded or restarted folder picks up writing where the
+        // last one left off
+        let respawn_output = match &file {
+            Some(file) if reloading || opt.folder_restart.is_some() => {
+                Some(file.try_clone().expect("duplicate output"))
+            }
+            _ => None,
+        };
+        // compressed, checksummed or reformatted output is written by us
+        let (stdout, piped_file) = match file {
+            Some(file)
+                if output_compression.is_some()
+                    || opt.checksum.is_some()
+                    || output_limit.is_some()
+                    || opt.output_format == Some(OutputFormat::Json) =>
+            {
+                (Stdio::piped(), Some((file, header)))
+            }
+            Some(mut file) => {
+                file.write_all(&header).expect("write output header");
+                (file.into(), None)
+            }
+            None => {
+                let mut stdout = io::stdout().lock();
+                stdout
+                    .write_all(&header)
+                    .and_then(|_| stdout.flush())
+                    .expect("write output header");
+                (Stdio::inherit(), None)
+            }
+        };
+
+        let mut presort = opt.presort.then(|| {
+            presort_command(delimiter, null)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap_or_else(|err| panic!("error spawn presort {}: {}", i, err))
+        });
+        let stdin = match &mut presort {
+            Some(sort) => sort.stdout.take().expect("presort stdout").into(),
+            // folders without a mapper, for empty input, get none
+            None if opt.mapper_output_passthrough => passthrough_outputs
+                .next()
+                .map_or(Stdio::null(), Stdio::from),
+            None => Stdio::piped(),
+        };
+        // the folder writes into --pipe-output-through instead
+        let (stdout, mut post) = match &opt.pipe_output_through {
+            Some(post_cmd) => {
+                let mut post = child_env
+                    .shell()
+                    .arg("-c")
+                    .arg(post_cmd)
+                    .stdin(Stdio::piped())
+                    .stdout(stdout)
+                    .spawn()
+                    .unwrap_or_else(|err| panic!("error spawn output pipe {}: {}", i, err));
+                let stdin = post.stdin.take().expect("output pipe stdin");
+                (stdin.into(), Some(post))
+            }
+            None => (stdout, None),
+        };
+
+        let mut cmd = child_env.shell();
+        cmd.arg("-c").arg(folder_cmd).stdin(stdin).stdout(stdout);
+        if opt.capture_child_stderr {
+            cmd.stderr(Stdio::piped());
+        }
+        if folder_timeout.is_some() {
+            cmd.process_group(0);
+        }
+        let mut child = cmd
+            .spawn()
+            .unwrap_or_else(|err| panic!("error spawn fold child {}: {}", i, err));
+        let watchdog = folder_timeout
+            .map(|timeout| Watchdog::spawn(child.id(), timeout, Arc::clone(shutdown)));
+        if let Some(stderr) = child.stderr.take() {
+            stderr_relays.push(relay_stderr(stderr, format!("[folder-{}]", i)));
+        }
+        let output_writer = piped_file.map(|(file, header)| {
+            let output = match &mut post {
+                Some(post) => post.stdout.take().expect("output pipe stdout"),
+                None => child.stdout.take().expect("folder stdout"),
+            };
+            let output: Box<dyn Read + Send> = match opt.output_format {
+                Some(OutputFormat::Json) => {
+                    Box::new(JsonLines::new(BufReader::new(output), i, terminator))
+                }
+                _ => Box::new(output),
+            };
+            let checksum = opt.checksum;
+            match output_limit {
+                Some(limit) => {
+                    let writer =
+                        CountingWriter::new(file, &write_paths[i], limit, terminator, header);
+                    thread::spawn(move || split_output(output, writer))
+                }
+                None => thread::spawn(move || {
+                    write_output(output, header, file, output_compression, checksum)
+                }),
+            }
+        });
+        FolderHandles {
+            child: Some(child),
+            sink: None,
+            presort,
+            output_writer,
+            watchdog,
+            respawn_output,
+            post,
+        }
+    }
+
+    /// Spawns every --folder after the first for partition `i`, each
+    /// writing to its own output.
+    fn spawn_fanout(&self, i: usize, stderr_relays: &mut Vec<JoinHandle<()>>) -> Vec<Child> {
+        let FolderSetup {
+            opt,
+            fanout_paths,
+            output_header,
+            ..
+        } = *self;
+        self.fanout_write_paths[i]
+            .iter()
+            // no --folder at all with --output-per-key
+            .zip(opt.folder.iter().skip(1))
+            .enumerate()
+            .map(|(m, (path, folder_cmd))| {
+                let child_env = self.env(i, Some(&fanout_paths[i][m]));
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .append(opt.append)
+                    .truncate(!opt.append)
+                    .open(path)
+                    .expect("write file");
+                file.write_all(output_header).expect("write output header");
+                let mut cmd = child_env.shell();
+                cmd.arg("-c")
+                    .arg(folder_cmd)
+                    .stdin(Stdio::piped())
+                    .stdout(file);
+                if opt.capture_child_stderr {
+                    cmd.stderr(Stdio::piped());
+                }
+                let mut child = cmd
+                    .spawn()
+                    .unwrap_or_else(|err| panic!("error spawn fold child {}: {}", i, err));
+                if let Some(stderr) = child.stderr.take() {
+                    let prefix = format!("[folder-{}.{}]", i, m + 1);
+                    stderr_relays.push(relay_stderr(stderr, prefix));
+                }
+                child
+            })
+            .collect()
+    }
+}
+
+/// What a folder's I/O thread needs, see [`feed_folder`].
+struct FolderFeed {
+    index: usize,
+    cmd: String,
+    child_env: ChildEnv,
+    reloader: Option<Arc<Reloader>>,
+    shutdown: Arc<AtomicBool>,
+    queues: Arc<QueueDepths>,
+    stats: Arc<Mutex<Stats>>,
+    stdin_prefix: Bytes,
+    stdin_suffix: Bytes,
+    max_restarts: usize,
+    count_lines: bool,
+    terminator: u8,
+}
+
+/// Writes every buffer received on `rx` to a folder and any `fanout` of
+/// it, replacing the folder on `--hot-reload` and restarting it after
+/// failures with `--folder-restart`, and then waits for it all to finish.
+fn feed_folder(
+    feed: FolderFeed,
+    handles: FolderHandles,
+    rx: Receiver<Bytes>,
+    mut fanout: Vec<Child>,
+) -> FolderResult {
+    let FolderFeed {
+        index: i,
+        mut cmd,
+        child_env,
+        reloader,
+        shutdown,
+        queues,
+        stats,
+        stdin_prefix,
+        stdin_suffix,
+        max_restarts,
+        count_lines,
+        terminator,
+    } = feed;
+    let FolderHandles {
+        mut child,
+        sink,
+        mut presort,
+        output_writer,
+        watchdog,
+        respawn_output,
+        post,
+    } = handles;
+    let respawn = |cmd: &str| {
+        let stdout = match &respawn_output {
+            Some(file) => file.try_clone().expect("duplicate output").into(),
+            None => Stdio::inherit(),
+        };
+        child_env
+            .shell()
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::piped())
+            .stdout(stdout)
+            .spawn()
+            .unwrap_or_else(|err| panic!("error spawn fold child {}: {}", i, err))
+    };
+    let mut child_stdin: Box<dyn Write + Send> = match (&mut presort, &mut child) {
+        (Some(sort), _) => Box::new(sort.stdin.take().expect("presort stdin")),
+        (None, Some(child)) => match child.stdin.take() {
+            Some(stdin) => Box::new(stdin),
+            // mapper output goes straight to the folder, so
+            // nothing's ever sent to it
+            None => Box::new(io::sink()),
+        },
+        (None, None) => sink.expect("folder socket or key files"),
+    };
+    if !fanout.is_empty() {
+        let mut stdins = vec![child_stdin];
+        stdins.extend(fanout.iter_mut().map(|child| {
+            Box::new(child.stdin.take().expect("child stdin")) as Box<dyn Write + Send>
+        }));
+        child_stdin = Box::new(FanOut(stdins));
+    }
+    let mut write_result = child_stdin.write_all(&stdin_prefix);
+    let mut generation = 0;
+    let mut replaced_status = None;
+    let mut restarts = 0;
+    while write_result.is_ok() {
+        let lines = match rx.recv() {
+            Ok(lines) => lines,
+            Err(_) => break,
+        };
+        queues.dequeue(i);
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        if let Some((latest, latest_cmd)) = reloader.as_ref().and_then(|r| r.newer_than(generation))
+        {
+            generation = latest;
+            cmd = latest_cmd;
+            write_result = child_stdin.write_all(&stdin_suffix);
+            drop(child_stdin);
+            let mut old = child.take().expect("folder child");
+            let status = old.wait().expect("wait");
+            if !status.success() {
+                replaced_status.get_or_insert(status);
+            }
+            let mut new = respawn(&cmd);
+            child_stdin = Box::new(new.stdin.take().expect("child stdin"));
+            child = Some(new);
+            write_result = write_result.and_then(|_| child_stdin.write_all(&stdin_prefix));
+            if write_result.is_err() {
+                break;
+            }
+        }
+        write_result = child_stdin.write_all(&lines);
+        while write_result.is_err() && restarts < max_restarts && !shutdown.load(Ordering::SeqCst) {
+            // the folder's gone, so start over with these lines
+            restarts += 1;
+            let status = child.take().expect("folder child").wait().expect("wait");
+            eprintln!(
+                "warning: folder {} exited early: {}, restarting (restart {} of {})",
+                i, status, restarts, max_restarts
+            );
+            let mut new = respawn(&cmd);
+            child_stdin = Box::new(new.stdin.take().expect("child stdin"));
+            child = Some(new);
+            write_result = child_stdin
+                .write_all(&stdin_prefix)
+                .and_then(|_| child_stdin.write_all(&lines));
+        }
+        if count_lines {
+            let nlines = lines.iter().filter(|&&b| b == terminator).count();
+            stats.lock().unwrap().lines_folded += nlines;
+        }
+    }
+    if write_result.is_ok() && !shutdown.load(Ordering::SeqCst) {
+        write_result = child_stdin
+            .write_all(&stdin_suffix)
+            .and_then(|_| child_stdin.flush());
+    }
+    drop(child_stdin);
+
+    // Failures are only checked after an interrupt would have
+    // been noticed, since children die on SIGINT too.
+    let presort_status = presort.map(|mut sort| sort.wait().expect("wait"));
+    let status = child.map(|mut child| child.wait().expect("wait"));
+    let post_status = post.map(|mut post| post.wait().expect("wait"));
+    let fanout_statuses: Vec<_> = fanout
+        .into_iter()
+        .map(|mut child| child.wait().expect("wait"))
+        .collect();
+    // a replaced or fanned out to folder's failure fails the
+    // whole run too
+    let status = replaced_status
+        .into_iter()
+        .chain(fanout_statuses)
+        .find(|status| !status.success())
+        .or(status);
+    let timed_out = watchdog.is_some_and(Watchdog::finish);
+    let output_result = output_writer.map_or(Ok(WrittenOutput::default()), |handle| {
+        handle.join().expect("output writer join")
+    });
+    FolderResult {
+        write_result,
+        status,
+        timed_out,
+        presort_status,
+        post_status,
+        output_result,
+    }
+}
+
+/// The CPUs child `i` runs on, taking turns among `cpu_sets`, or none to
+/// leave it be.
+fn pinned(cpu_sets: &[Vec<usize>], i: usize) -> Vec<usize> {
+    match cpu_sets.len() {
+        0 => Vec::new(),
+        n => cpu_sets[i % n].clone(),
+    }
+}
+
+fn main() {
+    let start = Instant::now();
+    let opt = Opt::from_args();
+    let verbose = opt.verbose;
+    let nthreads = opt.nthreads.unwrap_or(num_cpus::get_physical());
+    let mapper_cmd = opt.mapper.as_deref().unwrap_or("cat");
+    // only missing with --benchmark, which doesn't run one
+    let folder_cmd = opt.folder.first().map_or("", String::as_str);
+    if opt.folder.iter().any(|cmd| cmd.trim().is_empty()) {
+        eprintln!("error: --folder can't be empty");
+        process::exit(1);
+    }
+    if opt.on_mapper_failure != OnMapperFailure::Abort {
+        let unsupported = [
+            ("--batch-size", opt.batch_size.is_some()),
+            ("--mapper-workers", opt.mapper_workers > 1),
+            ("--mapper-output-passthrough", opt.mapper_output_passthrough),
+            ("--mapper-timeout", opt.mapper_timeout.is_some()),
+            ("--capture-child-stderr", opt.capture_child_stderr),
+        ];
+        if let Some((flag, _)) = unsupported.iter().find(|(_, set)| *set) {
+            eprintln!(
+                "error: {} can't be used with --on-mapper-failure skip or retry",
+                flag
+            );
+            process::exit(1);
+        }
+    }
+    if opt.folder.len() > 1 {
+        if opt.outprefix.is_none() {
+            eprintln!("error: --outprefix is required with more than one --folder");
+            process::exit(1);
+        }
+        let unsupported = [
+            ("--socket-folder", opt.socket_folder),
+            ("--presort", opt.presort),
+            ("--hot-reload", opt.hot_reload),
+            ("--folder-restart", opt.folder_restart.is_some()),
+            ("--pipe-output-through", opt.pipe_output_through.is_some()),
+            (
+                "--output-format json",
+                opt.output_format == Some(OutputFormat::Json),
+            ),
+            ("--mapper-output-passthrough", opt.mapper_output_passthrough),
+            ("--output-compression", opt.output_compression.is_some()),
+            ("--checksum", opt.checksum.is_some()),
+            ("--max-output-bytes", opt.max_output_bytes.is_some()),
+            ("--max-output-lines", opt.max_output_lines.is_some()),
+            ("--folder-timeout", opt.folder_timeout.is_some()),
+        ];
+        if let Some((flag, _)) = unsupported.iter().find(|(_, set)| *set) {
+            eprintln!("error: {} can't be used with more than one --folder", flag);
+            process::exit(1);
+        }
+    }
+    let bufsize = match opt.bufsize {
+        Some(kb) => kb * 1024,
+        None if opt.auto_bufsize => match available_memory() {
+            Ok(available) => {
+                let bufsize =
+                    (available / nthreads.max(1) / 4).clamp(MIN_AUTO_BUFSIZE, MAX_AUTO_BUFSIZE);
+                if verbose {
+                    eprintln!("auto bufsize: {} KB", bufsize / 1024);
+                }
+                bufsize
+            }
+            Err(e) => {
+                eprintln!("warning: --auto-bufsize can't read available memory: {}", e);
+                MIN_AUTO_BUFSIZE
+            }
+        },
+        None => 64 * 1024,
+    };
+    let bufsize_per_partition = opt.bufsize_per_partition;
+    let delimiter = opt.delimiter.unwrap_or(b' ');
+    let key = if let Some(pointer) = &opt.key_jsonptr {
+        Key::JsonPointer(pointer.clone())
+    } else if let Some(regex) = &opt.key_regex {
+        Key::Regex(regex.clone())
+    } else if let Some((start, end)) = opt.key_byte_range {
+        Key::ByteRange { start, end }
+    } else if !opt.key_fields.is_empty() {
+        Key::Fields {
+            delimiter,
+            fields: opt.key_fields.clone(),
+        }
+    } else {
+        Key::Delimited(delimiter)
+    };
+    // shorthands for transforms, applied after those listed
+    let shorthands = [
+        (opt.key_lowercase, KeyTransform::Lowercase),
+        (opt.key_trim, KeyTransform::Trim),
+        (opt.key_trim_start, KeyTransform::TrimStart),
+        (opt.key_trim_end, KeyTransform::TrimEnd),
+    ];
+    let shorthands = shorthands
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, transform)| transform);
+    let key = opt
+        .key_transform
+        .iter()
+        .chain(shorthands)
+        .fold(key, |key, &transform| {
+            Key::Transformed(Box::new(key), transform)
+        });
+    let mode = match opt.shard_mode {
+        Routing::RoundRobin if opt.consistent_hash => {
+            eprintln!("error: --consistent-hash requires --shard-mode hash");
+            process::exit(1)
+        }
+        Routing::RoundRobin => ShardMode::RoundRobin,
+        Routing::Hash if opt.consistent_hash => {
+            ShardMode::ConsistentHash(ConsistentHasher::new(opt.hasher))
+        }
+        Routing::Hash => ShardMode::Hash(opt.hasher),
+    };
+    let split_points = opt.split_points_file.as_ref().map(|path| {
+        if let ShardMode::RoundRobin = mode {
+            eprintln!("error: --split-points-file requires --shard-mode hash");
+            process::exit(1)
+        }
+        let split_points = read_split_points(path).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            process::exit(1)
+        });
+        Arc::new(split_points)
+    });
+    let partition_map = opt.partition_map_file.as_ref().map(|path| {
+        if let ShardMode::RoundRobin = mode {
+            eprintln!("error: --partition-map-file requires --shard-mode hash");
+            process::exit(1)
+        }
+        let partition_map = read_partition_map(path).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            process::exit(1)
+        });
+        Arc::new(partition_map)
+    });
+    if opt.verify && mode == ShardMode::RoundRobin {
+        eprintln!("error: --verify requires --shard-mode hash");
+        process::exit(1);
+    }
+    let queuesize = opt.queuesize;
+    let skew_threshold = opt.skew_threshold;
+    #[cfg(feature = "mmap")]
+    let mmap = opt.mmap;
+    #[cfg(not(feature = "mmap"))]
+    let mmap = false;
+    let flush_interval = opt.flush_interval.map(Duration::from_secs_f64);
+    let null = opt.null;
+    let line_limit = opt.max_line_length.map(|max_len| LineLimit {
+        max_len,
+        policy: opt.on_long_line,
+    });
+    let reloader = opt.hot_reload.then(|| {
+        let config = opt.hot_reload_config.clone().expect("hot reload config");
+        Reloader::spawn(config, folder_cmd).unwrap_or_else(|e| {
+            eprintln!("error: handling SIGUSR1: {}", e);
+            process::exit(1)
+        })
+    });
+    let output_header = match &opt.output_header_file {
+        Some(path) => Bytes::from(fs::read(path).unwrap_or_else(|e| {
+            eprintln!("error: reading {}: {}", path.display(), e);
+            process::exit(1)
+        })),
+        None => Bytes::new(),
+    };
+    let output_limit = Some(OutputLimit {
+        max_bytes: opt.max_output_bytes,
+        max_lines: opt.max_output_lines,
+    })
+    .filter(|limit| limit.max_bytes.is_some() || limit.max_lines.is_some());
+    let window = opt.window.map(|size| Window {
+        size,
+        format: opt.window_format,
+    });
+    let line_counter = Arc::new(AtomicU64::new(0));
+    let shell = opt.shell.clone().unwrap_or_else(|| {
+        env::var_os("SHELL")
+            .filter(|shell| !shell.is_empty())
+            .map_or_else(|| PathBuf::from("/bin/sh"), PathBuf::from)
+    });
+    if !is_executable(&shell) {
+        eprintln!("error: shell {} is not an executable file", shell.display());
+        process::exit(1);
+    }
+    let child_env = ChildEnv {
+        shell,
+        vars: opt.env.clone(),
+        clear: opt.clear_env,
+        memory_limit: None,
+        cpus: Vec::new(),
+    };
+    let cpu_sets = match &opt.cpu_affinity {
+        Some(_) if !cfg!(target_os = "linux") => {
+            eprintln!("warning: --cpu-affinity is only supported on Linux, ignoring it");
+            Vec::new()
+        }
+        Some(affinity) => affinity.cpu_sets().unwrap_or_else(|e| {
+            eprintln!(
+                "warning: --cpu-affinity can't read the NUMA topology: {}",
+                e
+            );
+            Vec::new()
+        }),
+        None => Vec::new(),
+    };
+    if let Some(cpus) = cpu_sets.iter().find(|cpus| !affinity::any_allowed(cpus)) {
+        eprintln!("error: --cpu-affinity CPUs {:?} are all unavailable", cpus);
+        process::exit(1);
+    }
+    if let Some(socket) = &opt.serve_folder {
+        let output = opt.serve_output.as_ref().expect("required by clap");
+        if let Err(e) = socket::serve(socket, output, folder_cmd, &child_env) {
+            eprintln!("error: serving folder on {}: {}", socket.display(), e);
+            process::exit(1);
+        }
+        return;
+    }
+    let folder_timeout = opt.folder_timeout.map(Duration::from_secs_f64);
+    let terminator = if null { b'\0' } else { b'\n' };
+    if opt.benchmark {
+        let mut input = Vec::with_capacity(opt.bench_nlines * opt.bench_line_size);
+        for i in 0..opt.bench_nlines {
+            let start = input.len();
+            write!(input, "key_{:06} value_{:06}", i, i).expect("write to vec");
+            let padded = start + opt.bench_line_size.saturating_sub(1);
+            input.resize(input.len().max(padded), b'x');
+            input.push(terminator);
+        }
+        let shard = if null {
+            sharder::shard_nullsep
+        } else {
+            sharder::shard
+        };
+        let npartitions = opt.nfolders.unwrap_or(nthreads);
+        let start = Instant::now();
+        let stats = shard(
+            &input[..],
+            npartitions,
+            bufsize,
+            &key,
+            mode,
+            ShardOptions {
+                bufsize_per_partition,
+                skew_threshold,
+                flush_interval,
+                line_limit,
+                window,
+                ..ShardOptions::default()
+            },
+            |_, _: Bytes| (),
+        );
+        let secs = start.elapsed().as_secs_f64();
+        println!(
+            "sharded {} lines ({} bytes) into {} partitions in {:.3}s: {:.1} MB/s, {:.2}M lines/s",
+            stats.lines,
+            stats.bytes,
+            npartitions,
+            secs,
+            stats.bytes as f64 / secs / 1e6,
+            stats.lines as f64 / secs / 1e6
+        );
+        return;
+    }
+
+    // TODO: Assume bufsize is fixed due to memory constraints.
+    //
+    // We could play with queuesize and mapper:folder ratio tuning.
+    // For map-constrained tasks, reducing folders past 1:1 ratio
+    // probably doesn't help since folders sitting idle don't hurt anyone.
+    // However, for fold-constrained tasks lower mapper ratios like 1:2, 1:4,
+    // and etc. are interesting since memory usage and block time could be
+    // reduced after dynamic tuning. Then for a given ideal mapper:folder
+    // ratio, which could be derived with Little's law, and a given
+    // variance in mapper speed (normalized by reducer speed), after
+    // assuming the hash is uniform, one can compute variance in queue
+    // lengths given a Poisson process setup. This means that computing
+    // statistics about mapper/folder speeds is enough to back out
+    // the ideal mapper:folder ratio and queue size (queue size chosen such
+    // that blocking is avoided with 99% probability at any fixed steady-state
+    // time).
+    //
+    // Above approach can work simply assuming mapper/folder speeds are constant
+    // over time quanta holding past trends. Otherwise, a control theory
+    // approach could be used.
+    //
+    // This would be fun to investigate more deeply, but I have yet to encounter
+    // a folder-constrained task IRL to test this on.
+
+    // Allow enough chunks for parallelism but not so few the chunksize
+    // is small.
+    let read_chunk_size = 16 * 1024;
+
+    // The stdin buffer must outlive the mappers reading from it.
+    let stdin_buffer;
+    let stream_buffer;
+    let read_stdin =
+        opt.infile.is_empty() && opt.indir.is_empty() && opt.infile_partition.is_empty();
+    let mut infiles = if read_stdin {
+        stdin_buffer = buffer_input(io::stdin().lock());
+        vec![stdin_buffer.path().to_owned()]
+    } else {
+        collect_infiles(&opt.infile, &opt.indir).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            process::exit(1)
+        })
+    };
+    let chunk_budget = opt.max_chunks.map_or(nthreads, |max| max.min(nthreads));
+    let chunkify_as = |paths: &[PathBuf], compression| {
+        let chunks = if paths.is_empty() {
+            Ok(Vec::new())
+        } else if opt.null {
+            fileblocks::chunkify_multiple_nullsep(paths, chunk_budget, read_chunk_size, compression)
+        } else {
+            fileblocks::chunkify_multiple(paths, chunk_budget, read_chunk_size, compression)
+        };
+        chunks.unwrap_or_else(|e| {
+            eprintln!("error: chunking input: {}", e);
+            process::exit(1)
+        })
+    };
+    let chunkify = |paths: &[PathBuf]| chunkify_as(paths, opt.compression);
+    let mut chunks = chunkify(&infiles);
+    let skip_lines = if opt.input_skip_header {
+        1
+    } else {
+        opt.input_skip_lines.unwrap_or(0)
+    };
+    if opt.header_output.is_some() && skip_lines == 0 {
+        eprintln!("error: --header-output requires --input-skip-lines or --input-skip-header");
+        process::exit(1);
+    }
+    if skip_lines > 0 && chunks.first().is_some_and(FileChunk::is_stream) {
+        // a stream can only be read once, so like stdin, it's buffered to
+        // skip what's needed
+        let index = infiles
+            .iter()
+            .position(|path| path == chunks[0].path())
+            .expect("chunked infile");
+        stream_buffer = buffer_input(chunks[0].file());
+        infiles[index] = stream_buffer.path().to_owned();
+        chunks = chunkify(&infiles);
+    }
+    if skip_lines > 0 {
+        // the lines to skip may run past the first chunk of the first file
+        let first_path = chunks.first().map(|chunk| chunk.path().to_owned());
+        let mut header = Vec::new();
+        let mut remaining = skip_lines;
+        let mut emptied = 0;
+        for chunk in &mut chunks {
+            if first_path.as_deref() != Some(chunk.path()) {
+                break;
+            }
+            let skipped = chunk
+                .skip_lines(remaining, &mut header)
+                .unwrap_or_else(|e| {
+                    eprintln!("error: reading {}: {}", chunk.path().display(), e);
+                    process::exit(1)
+                });
+            if skipped < remaining || chunk.nbytes() == 0 {
+                emptied += 1;
+            }
+            remaining -= skipped;
+            if remaining == 0 {
+                break;
+            }
+        }
+        chunks.drain(..emptied);
+        if let Some(path) = &opt.header_output {
+            if let Err(e) = fs::write(path, header) {
+                eprintln!("error: writing {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+    let shuffle_buffer;
+    if opt.input_shuffle && !chunks.is_empty() {
+        shuffle_buffer = shuffle_input(&chunks, terminator, opt.shuffle_seed, mmap);
+        chunks = chunkify_as(&[shuffle_buffer.path().to_owned()], Some(Compression::None));
+    }
+    // every file gets a chunk of its own, so there may be too many
+    let merge_buffers: Vec<_>;
+    if let Some(max_chunks) = opt.max_chunks.filter(|&max| chunks.len() > max) {
+        let nchunks = chunks.len();
+        merge_buffers = (0..max_chunks)
+            .map(|group| {
+                let (start, stop) = (
+                    group * nchunks / max_chunks,
+                    (group + 1) * nchunks / max_chunks,
+                );
+                merge_chunks(&chunks[start..stop], terminator)
+            })
+            .collect();
+        chunks = merge_buffers
+            .iter()
+            .flat_map(|buffer| {
+                let path = [buffer.path().to_owned()];
+                let compression = Some(Compression::None);
+                let chunks = if opt.null {
+                    fileblocks::chunkify_multiple_nullsep(&path, 1, read_chunk_size, compression)
+                } else {
+                    fileblocks::chunkify_multiple(&path, 1, read_chunk_size, compression)
+                };
+                chunks.expect("chunk merged input")
+            })
+            .collect();
+    }
+    // the folder each mapper's output goes to, if it isn't sharded
+    let mut chunk_partitions = vec![None; chunks.len()];
+    for (index, path) in &opt.infile_partition {
+        let partition_chunks = chunkify(std::slice::from_ref(path));
+        chunk_partitions.extend(partition_chunks.iter().map(|_| Some(*index)));
+        chunks.extend(partition_chunks);
+    }
+    // as reported, unlike the mappers there are
+    let requested_nthreads = nthreads;
+    // smaller b/c of min bufsize, but empty input still gets a folder
+    let nthreads = chunks.len().max(1);
+    let mapper_overrides: HashMap<usize, String> = opt.mapper_override.iter().cloned().collect();
+    if let Some(index) = mapper_overrides
+        .keys()
+        .find(|&&index| index >= chunks.len())
+    {
+        eprintln!(
+            "error: mapper override index {} out of range for {} mappers",
+            index,
+            chunks.len()
+        );
+        process::exit(1);
+    }
+    let mapper_cmds: Vec<_> = (0..chunks.len())
+        .map(|i| mapper_overrides.get(&i).map_or(mapper_cmd, String::as_str))
+        .collect();
+    let output_compression = opt.output_compression.map(|compression| {
+        let level = opt.compression_level.unwrap_or(match compression {
+            Compression::Gzip => 6,
+            _ => 3,
+        });
+        let max_level = match compression {
+            Compression::Gzip => 9,
+            _ => 22,
+        };
+        if level > max_level || (compression == Compression::Zstd && level == 0) {
+            eprintln!(
+                "error: compression level {} out of range for {}",
+                level, compression
+            );
+            process::exit(1);
+        }
+        (compression, level)
+    });
+    let requested_nfolders = match &split_points {
+        Some(points) => Some(points.len() + 1),
+        None => opt.nfolders,
+    };
+    // Without an output prefix, the lone folder inherits our stdout, and
+    // there are no output paths at all.
+    let (nfolders, output_paths): (_, Vec<_>) = match &opt.outprefix {
+        // the lone partition is written out by key
+        Some(_) if opt.output_per_key => (1, Vec::new()),
+        None if opt.merge_output.is_some() || opt.folder_output_to_stdout => {
+            (requested_nfolders.unwrap_or(nthreads), Vec::new())
+        }
+        Some(outprefix) => {
+            let nfolders = requested_nfolders.unwrap_or(nthreads);
+            let extension = match opt.output_compression {
+                Some(Compression::Gzip) => ".gz",
+                Some(Compression::Zstd) => ".zst",
+                _ => "",
+            };
+            let paths = (0..nfolders)
+                .map(|i| {
+                    let mut path = pipeline::output_path(outprefix, i, nfolders).into_os_string();
+                    if opt.folder.len() > 1 {
+                        path.push("_folder0");
+                    }
+                    path.push(extension);
+                    PathBuf::from(path)
+                })
+                .collect();
+            (nfolders, paths)
+        }
+        None if requested_nfolders.unwrap_or(1) == 1 => (1, Vec::new()),
+        None => {
+            eprintln!(
+                "error: --outprefix, --merge-output, or --folder-output-to-stdout is required \
+                 with more than one folder"
+            );
+            process::exit(1);
+        }
+    };
+    if let Some((key, index)) = partition_map
+        .iter()
+        .flat_map(|map| map.iter())
+        .find(|&(_, &index)| index >= nfolders)
+    {
+        eprintln!(
+            "error: partition map index {} of key {} out of range for {} folders",
+            index,
+            String::from_utf8_lossy(key),
+            nfolders
+        );
+        process::exit(1);
+    }
+    if let Some((index, path)) = opt
+        .infile_partition
+        .iter()
+        .find(|&&(index, _)| index >= nfolders)
+    {
+        eprintln!(
+            "error: infile partition index {} of {} out of range for {} folders",
+            index,
+            path.display(),
+            nfolders
+        );
+        process::exit(1);
+    }
+    if opt.mapper_output_passthrough && (opt.mapper_workers > 1 || nfolders != nthreads) {
+        eprintln!(
+            "error: --mapper-output-passthrough needs one worker per mapper, and as many \
+             folders as mappers, not {} folders for {} mappers",
+            nfolders, nthreads
+        );
+        process::exit(1);
+    }
+    if opt.dry_run {
+        dry_run(
+            &chunks,
+            &chunk_partitions,
+            &mapper_cmds,
+            folder_cmd,
+            requested_nthreads,
+            nfolders,
+            &output_paths,
+            opt.dry_run_json,
+        );
+        return;
+    }
+    let write_paths: Vec<_> = if opt.atomic {
+        output_paths.iter().map(|path| tmp_path(path)).collect()
+    } else {
+        output_paths.clone()
+    };
+    // the outputs of every --folder after the first, by partition
+    let fanout_paths: Vec<Vec<_>> = (0..nfolders)
+        .map(|i| match &opt.outprefix {
+            Some(outprefix) => (1..opt.folder.len())
+                .map(|m| {
+                    let mut path = pipeline::output_path(outprefix, i, nfolders).into_os_string();
+                    path.push(format!("_folder{}", m));
+                    PathBuf::from(path)
+                })
+                .collect(),
+            None => Vec::new(),
+        })
+        .collect();
+    let fanout_write_paths: Vec<Vec<_>> = fanout_paths
+        .iter()
+        .map(|paths| {
+            paths
+                .iter()
+                .map(|path| {
+                    if opt.atomic {
+                        tmp_path(path)
+                    } else {
+                        path.clone()
+                    }
+                })
+                .collect()
+        })
+        .collect();
+
+    // On interrupt, children in our process group will have received it
+    // too, so everything below winds down on its own once it notices the
+    // shutdown flag. Just in case it doesn't, exit after a timeout anyway.
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let mut cleanup_paths = if opt.append || opt.socket_folder {
+        Vec::new()
+    } else {
+        let fanout = fanout_write_paths.iter().flatten().cloned();
+        write_paths.iter().cloned().chain(fanout).collect()
+    };
+    cleanup_paths.extend(read_stdin.then(|| infiles[0].clone()));
+    cleanup_paths.extend(opt.merge_output.clone());
+    let merged = match &opt.merge_output {
+        Some(path) => Some(File::create(path).unwrap_or_else(|e| {
+            eprintln!("error: creating {}: {}", path.display(), e);
+            process::exit(1)
+        })),
+        None if opt.folder_output_to_stdout => {
+            let stdout = io::stdout().as_fd().try_clone_to_owned();
+            Some(File::from(stdout.expect("duplicate stdout")))
+        }
+        None => None,
+    };
+    let mut spools = Vec::new();
+    {
+        let shutdown = Arc::clone(&shutdown);
+        let cleanup_paths = cleanup_paths.clone();
+        let timeout = Duration::from_secs_f64(opt.shutdown_timeout);
+        ctrlc::set_handler(move || {
+            if shutdown.swap(true, Ordering::SeqCst) {
+                // interrupted twice, don't wait around
+                interrupted_exit(&cleanup_paths);
+            }
+            let cleanup_paths = cleanup_paths.clone();
+            thread::spawn(move || {
+                thread::sleep(timeout);
+                interrupted_exit(&cleanup_paths);
+            });
+        })
+        .expect("set interrupt handler");
+    }
+
+    let spooled = opt.on_mapper_failure != OnMapperFailure::Abort;
+    let mut stderr_relays = Vec::new();
+    let Mappers {
+        processes: mut mapper_processes,
+        feeders: mapper_feeders,
+        watchdogs: mapper_watchdogs,
+        cursors,
+        threads: mapper_threads,
+        outputs: threaded_outputs,
+    } = spawn_mappers(
+        &opt,
+        &chunks,
+        &mapper_cmds,
+        &child_env,
+        &cpu_sets,
+        mmap,
+        terminator,
+        &shutdown,
+        &mut stderr_relays,
+    );
+
+    // mapper output which goes straight to its folder isn't sharded at all
+    let mut passthrough_outputs = Vec::new();
+    if opt.mapper_output_passthrough {
+        passthrough_outputs = mapper_processes
+            .iter_mut()
+            .flatten()
+            .map(|child| child.stdout.take().unwrap())
+            .collect();
+    }
+    let mut passthrough_outputs = passthrough_outputs.into_iter();
+
+    let mut mapper_mergers = Vec::new();
+    let mut threaded_outputs = threaded_outputs.into_iter();
+    let mapper_outputs: Vec<_> = mapper_processes
+        .iter_mut()
+        .filter(|_| !opt.mapper_output_passthrough)
+        .map(|children| {
+            if opt.batch_size.is_some() || spooled {
+                return threaded_outputs.next().expect("threaded mapper output");
+            }
+            let mut outputs: Vec<_> = children
+                .iter_mut()
+                .map(|child| child.stdout.take().unwrap())
+                .collect();
+            if outputs.len() == 1 {
+                Box::new(outputs.pop().unwrap()) as Box<dyn Read + Send>
+            } else {
+                let (merged, threads) = merge_outputs(outputs, terminator);
+                mapper_mergers.extend(threads);
+                Box::new(merged)
+            }
+        })
+        .collect();
+
+    let (txs, rxs): (Vec<_>, Vec<_>) = (0..nfolders).map(|_| sync_channel(queuesize)).unzip();
+    let stats = Arc::new(Mutex::new(Stats::new(nfolders)));
+    let queues = Arc::new(QueueDepths::new(nfolders, queuesize));
+    let (progress_tx, progress_rx) = sync_channel(0);
+    let reporter = if opt.progress {
+        Some(progress::spawn_reporter(
+            Arc::clone(&stats),
+            cursors.into_iter().map(|(_, cursor)| cursor).collect(),
+            progress_rx,
+        ))
+    } else if opt.progress_bar {
+        progress::spawn_bars(Arc::clone(&stats), cursors, progress_rx)
+    } else {
+        None
+    };
+    let count_lines = opt.progress_bar;
+
+    let txs_ref = Arc::new(txs);
+    let discard_file = opt.discard_file.as_ref().map(|path| {
+        let file = File::create(path).unwrap_or_else(|e| {
+            eprintln!("error: creating {}: {}", path.display(), e);
+            process::exit(1)
+        });
+        Arc::new(Mutex::new(file))
+    });
+    let discarded_lines = Arc::new(AtomicUsize::new(0));
+    let mapper_output_threads: Vec<_> = mapper_outputs
+        .into_iter()
+        .zip(chunk_partitions.iter().copied())
+        .enumerate()
+        .map(|(i, (output, partition))| {
+            let line_numbers = opt.line_number_prefix.then(|| LineNumbers {
+                counter: Arc::clone(&line_counter),
+                source: opt.line_number_prefix_source.then_some(i),
+            });
+            let txs_ref_clone = Arc::clone(&txs_ref);
+            let stats = Arc::clone(&stats);
+            let queues = Arc::clone(&queues);
+            let key = key.clone();
+            let shutdown = Arc::clone(&shutdown);
+            let combiner = opt.combiner.clone();
+            let child_env = child_env.clone();
+            let split_points = split_points.clone();
+            let partition_map = partition_map.clone();
+            let skip_empty_keys = opt.skip_empty_keys;
+            let split_on_key_change = opt.split_on_key_change;
+            let discard_file = discard_file.clone();
+            let discarded_lines = Arc::clone(&discarded_lines);
+            thread::spawn(move || {
+                let output = BufReader::new(output);
+                let mut output: Box<dyn BufRead + Send> = if skip_empty_keys {
+                    Box::new(BlankKeyFilter::new(
+                        output,
+                        key.clone(),
+                        terminator,
+                        discard_file,
+                        discarded_lines,
+                    ))
+                } else {
+                    Box::new(output)
+                };
+                let txs_ref_local = txs_ref_clone.deref();
+                let shard = if null {
+                    sharder::shard_nullsep
+                } else {
+                    sharder::shard
+                };
+                // Output of a pre-partitioned file is still buffered like
+                // any other, but all into a single partition.
+                let (npartitions, mode) = match partition {
+                    // windows still follow keys
+                    Some(_) if window.is_some() => (1, mode),
+                    Some(_) => (1, ShardMode::RoundRobin),
+                    None => (nfolders, mode),
+                };
+                let mut combine_error = None;
+                let mut send = |ix, buf: Bytes| {
+                    let ix = partition.unwrap_or(ix);
+                    if shutdown.load(Ordering::SeqCst) || combine_error.is_some() {
+                        // drain mapper output without sending it along
+                        return;
+                    }
+                    let buf = match &combiner {
+                        Some(cmd) => match combine(cmd, &child_env, buf, terminator) {
+                            Ok(buf) => buf,
+                            Err(e) => {
+                                combine_error = Some(e);
+                                return;
+                            }
+                        },
+                        None => buf,
+                    };
+                    let nbytes = buf.len();
+                    let nlines = if count_lines {
+                        buf.iter().filter(|&&b| b == terminator).count()
+                    } else {
+                        0
+                    };
+                    queues.enqueue(ix);
+                    // A failed send means the folder is gone, which
+                    // its thread reports.
+                    let (sent, blocked) = match txs_ref_local[ix].try_send(buf) {
+                        Ok(()) => (true, false),
+                        Err(TrySendError::Full(buf)) => (txs_ref_local[ix].send(buf).is_ok(), true),
+                        Err(TrySendError::Disconnected(_)) => (false, false),
+                    };
+                    if !sent {
+                        queues.dequeue(ix);
+                    }
+                    let mut stats = stats.lock().unwrap();
+                    stats.lines_sent[ix] += 1;
+                    stats.lines_blocking[ix] += blocked as usize;
+                    stats.bytes_sent += nbytes;
+                    stats.lines_mapped += nlines;
+                };
+                let options = ShardOptions {
+                    bufsize_per_partition,
+                    skew_threshold,
+                    flush_interval,
+                    line_limit,
+                    window,
+                    line_numbers: line_numbers.as_ref(),
+                    partition_map: None,
+                };
+                let shard_stats = match (&split_points, partition) {
+                    _ if split_on_key_change => {
+                        sharder::shard_by_key_run(&mut output, npartitions, &key, mode, &mut send)
+                    }
+                    (Some(split_points), None) => sharder::shard_sorted(
+                        &mut output,
+                        split_points,
+                        bufsize,
+                        &key,
+                        options,
+                        &mut send,
+                    ),
+                    _ => shard(
+                        &mut output,
+                        npartitions,
+                        bufsize,
+                        &key,
+                        mode,
+                        ShardOptions {
+                            // pre-partitioned output has a single partition
+                            partition_map: partition_map.as_deref().filter(|_| partition.is_none()),
+                            ..options
+                        },
+                        &mut send,
+                    ),
+                };
+                // a long line stops sharding early, but the mapper should
+                // still get to finish
+                io::copy(&mut output, &mut io::sink()).expect("drain mapper output");
+                (shard_stats, combine_error)
+            })
+        })
+        .collect();
+
+    // created as keys turn up, see --output-per-key
+    let key_file_paths = Arc::new(Mutex::new(Vec::new()));
+    let folders = FolderSetup {
+        opt: &opt,
+        folder_cmd,
+        child_env: &child_env,
+        cpu_sets: &cpu_sets,
+        nfolders,
+        key: &key,
+        terminator,
+        delimiter,
+        null,
+        output_paths: &output_paths,
+        write_paths: &write_paths,
+        fanout_paths: &fanout_paths,
+        fanout_write_paths: &fanout_write_paths,
+        output_header: &output_header,
+        merged: merged.as_ref(),
+        output_compression,
+        output_limit,
+        reloading: reloader.is_some(),
+        folder_timeout,
+        shutdown: &shutdown,
+        key_file_paths: &key_file_paths,
+    };
+    let folder_processes: Vec<_> = (0..nfolders)
+        .map(|i| folders.spawn(i, &mut spools, &mut passthrough_outputs, &mut stderr_relays))
+        .collect();
+    // every --folder after the first gets the same input
+    let fanout_children: Vec<_> = (0..nfolders)
+        .map(|i| folders.spawn_fanout(i, &mut stderr_relays))
+        .collect();
+
+    // must be both I/O thread to manage livelock from stdin EOF
+    // expectation of folder procs
+    let folder_input_output_threads: Vec<_> = folder_processes
+        .into_iter()
+        .zip(rxs)
+        .zip(fanout_children)
+        .enumerate()
+        .map(|(i, ((handles, rx), fanout))| {
+            let feed = FolderFeed {
+                index: i,
+                cmd: folder_cmd.to_owned(),
+                child_env: folders.env(i, output_paths.get(i)),
+                reloader: reloader.clone(),
+                shutdown: Arc::clone(&shutdown),
+                queues: Arc::clone(&queues),
+                stats: Arc::clone(&stats),
+                stdin_prefix: opt.folder_stdin_prefix.clone().unwrap_or_default(),
+                stdin_suffix: opt.folder_stdin_suffix.clone().unwrap_or_default(),
+                max_restarts: opt.folder_restart.unwrap_or(0),
+                count_lines,
+                terminator,
+            };
+            thread::spawn(move || feed_folder(feed, handles, rx, fanout))
+        })
+        .collect();
+
+    // a mapper fails if any of its workers do
+    let mut mapper_statuses: Vec<Option<(ExitStatus, bool)>> = vec![None; chunks.len()];
+    for (mut child, (i, watchdog)) in mapper_processes.into_iter().flatten().zip(mapper_watchdogs) {
+        let status = child.wait().expect("wait");
+        let timed_out = watchdog.is_some_and(Watchdog::finish);
+        match &mut mapper_statuses[i] {
+            Some((first, any_timed_out)) => {
+                if first.success() {
+                    *first = status;
+                }
+                *any_timed_out |= timed_out;
+            }
+            slot => *slot = Some((status, timed_out)),
+        }
+    }
+    // threaded mappers have no process of their own to wait on
+    let mapper_statuses: Vec<_> = mapper_statuses
+        .into_iter()
+        .map(Option::unwrap_or_default)
+        .collect();
+    let mapper_errors: Vec<_> = mapper_threads
+        .into_iter()
+        .map(|handle| handle.join().expect("mapper thread join").err())
+        .collect();
+    mapper_feeders
+        .into_iter()
+        .for_each(|handle| handle.join().expect("map feeder join"));
+    let (shard_stats, combine_errors): (Vec<_>, Vec<_>) = mapper_output_threads
+        .into_iter()
+        .map(|handle| handle.join().expect("map output join"))
+        .unzip();
+    mapper_mergers
+        .into_iter()
+        .for_each(|handle| handle.join().expect("map merger join"));
+    let keyless_lines: usize = shard_stats.iter().map(|s| s.keyless_lines).sum();
+    let long_lines: usize = shard_stats.iter().map(|s| s.long_lines).sum();
+
+    let txs = Arc::try_unwrap(txs_ref).expect("final reference");
+    drop(txs); // ensure hangup of transmission channel
+
+    // Closures here own the fold processes
+    let folder_results: Vec<_> = folder_input_output_threads
+        .into_iter()
+        .map(|handle| handle.join().expect("fold join"))
+        .collect();
+    stderr_relays
+        .into_iter()
+        .for_each(|handle| handle.join().expect("stderr relay join"));
+    // progress covers the folders too
+    drop(progress_tx);
+    if let Some(reporter) = reporter {
+        reporter.join().expect("progress join");
+    }
+    if opt.estimate_cardinality {
+        let mut distinct_keys = HyperLogLog::new();
+        for stats in &shard_stats {
+            distinct_keys.merge(&stats.distinct_keys);
+        }
+        eprintln!(
+            "~{:.0} distinct keys in {} lines ({} bytes)",
+            distinct_keys.estimate(),
+            shard_stats.iter().map(|s| s.lines).sum::<usize>(),
+            shard_stats.iter().map(|s| s.bytes).sum::<usize>()
+        );
+    }
+    if keyless_lines > 0 {
+        eprintln!(
+            "warning: {} lines had no key and were sent to folder 0",
+            keyless_lines
+        );
+    }
+    let discarded_lines = discarded_lines.load(Ordering::Relaxed);
+    if discarded_lines > 0 {
+        eprintln!(
+            "warning: {} lines with empty keys were skipped",
+            discarded_lines
+        );
+    }
+    if long_lines > 0 {
+        let handled = match opt.on_long_line {
+            LongLines::Truncate => Some("truncated"),
+            LongLines::Skip => Some("skipped"),
+            LongLines::Error => None,
+        };
+        if let Some(handled) = handled {
+            eprintln!(
+                "warning: {} lines over --max-line-length were {}",
+                long_lines, handled
+            );
+        }
+    }
+    for result in &folder_results {
+        if let Ok(output) = &result.output_result {
+            cleanup_paths.extend(output.rotated_paths.iter().cloned());
+        }
+    }
+    cleanup_paths.extend(key_file_paths.lock().unwrap().drain(..));
+
+    if shutdown.load(Ordering::SeqCst) {
+        interrupted_exit(&cleanup_paths);
+    }
+    let mut failed = false;
+    for (i, (status, timed_out)) in mapper_statuses.iter().enumerate() {
+        if *timed_out {
+            eprintln!(
+                "error: mapper {} timed out after {}s",
+                i,
+                opt.mapper_timeout.unwrap()
+            );
+            failed = true;
+        } else if !status.success() {
+            eprintln!(
+                "error: mapper {} failed: {}",
+                i,
+                describe_exit(*status, opt.max_mapper_memory)
+            );
+            failed = true;
+        }
+    }
+    for (i, error) in mapper_errors.iter().enumerate() {
+        match error {
+            Some(e) if opt.on_mapper_failure == OnMapperFailure::Skip => {
+                eprintln!(
+                    "warning: skipped the output of mapper {}, which failed: {}",
+                    i, e
+                )
+            }
+            Some(e) => {
+                eprintln!("error: mapper {} failed: {}", i, e);
+                failed = true;
+            }
+            None => (),
+        }
+    }
+    if opt.on_long_line == LongLines::Error {
+        for (i, stats) in shard_stats.iter().enumerate() {
+            if stats.long_lines > 0 {
+                eprintln!(
+                    "error: mapper {} output a line over --max-line-length {}",
+                    i,
+                    opt.max_line_length.unwrap()
+                );
+                failed = true;
+            }
+        }
+    }
+    for (i, error) in combine_errors.iter().enumerate() {
+        if let Some(e) = error {
+            eprintln!("error: combiner for mapper {} failed: {}", i, e);
+            failed = true;
+        }
+    }
+    for (i, result) in folder_results.iter().enumerate() {
+        if result.timed_out {
+            // writing to the killed folder failed too, which is no news
+            eprintln!(
+                "error: folder {} timed out after {}s",
+                i,
+                opt.folder_timeout.unwrap()
+            );
+            failed = true;
+            continue;
+        }
+        if let Err(e) = &result.write_result {
+            if opt.output_per_key {
+                eprintln!("error: writing output per key: {}", e);
+            } else {
+                eprintln!("error: writing to folder {}: {}", i, e);
+            }
+            failed = true;
+        }
+        if let Some(status) = result.presort_status.filter(|status| !status.success()) {
+            eprintln!("error: presort for folder {} failed: {}", i, status);
+            failed = true;
+        }
+        if let Some(status) = result.post_status.filter(|status| !status.success()) {
+            eprintln!("error: output pipe for folder {} failed: {}", i, status);
+            failed = true;
+        }
+        if let Err(e) = &result.output_result {
+            eprintln!("error: writing output of folder {}: {}", i, e);
+            failed = true;
+        }
+        if let Some(status) = result.status.filter(|status| !status.success()) {
+            eprintln!(
+                "error: folder {} failed: {}",
+                i,
+                describe_exit(status, opt.max_folder_memory)
+            );
+            failed = true;
+        }
+    }
+    if failed {
+        // partial output is worse than none for downstream consumers
+        remove_files(&cleanup_paths);
+        eprintln!("removed partial output");
+        process::exit(1);
+    }
+    if let Some(mut merged) = merged {
+        // stdout may well be a pipe, but it can't be seeked then either
+        if opt.merge_output.is_some() {
+            merged.seek(SeekFrom::End(0)).expect("seek merged output");
+        }
+        for mut spool in spools {
+            spool.seek(SeekFrom::Start(0)).expect("seek output spool");
+            io::copy(&mut spool, &mut merged).expect("merge output");
+        }
+    }
+    if opt.atomic {
+        let fanout = fanout_write_paths
+            .iter()
+            .flatten()
+            .zip(fanout_paths.iter().flatten());
+        for (tmp, path) in write_paths.iter().zip(&output_paths).chain(fanout) {
+            fs::rename(tmp, path).expect("rename output");
+        }
+    }
+    if opt.verify {
+        let misrouted = verify_outputs(
+            &output_paths,
+            &key,
+            mode,
+            split_points.as_ref().map(|points| points.as_slice()),
+            partition_map.as_deref(),
+            terminator,
+        );
+        if misrouted > 0 {
+            eprintln!("error: {} lines were in the wrong folder", misrouted);
+            process::exit(1);
+        }
+    }
+    if opt.checksum.is_some() {
+        let lines: String = folder_results
+            .iter()
+            .zip(&output_paths)
+            .filter_map(|(result, path)| match &result.output_result {
+                Ok(WrittenOutput {
+                    checksum: Some(checksum),
+                    ..
+                }) => Some(format!("{}  {}\n", checksum, path.display())),
+                _ => None,
+            })
+            .collect();
+        match &opt.checksum_file {
+            Some(path) => {
+                if let Err(e) = fs::write(path, lines) {
+                    eprintln!("error: writing {}: {}", path.display(), e);
+                    process::exit(1);
+                }
+            }
+            None => print!("{}", lines),
+        }
+    }
+
+    let stats = Arc::try_unwrap(stats)
+        .expect("final reference")
+        .into_inner()
+        .unwrap();
+    if verbose {
+        println!(
+            "sent {:?}\nblock {:?}\nmax queue depth {:?}",
+            stats.lines_sent,
+            stats.lines_blocking,
+            queues.max_depths()
+        );
+    }
+    if let Some(path) = &opt.stats_file {
+        // a pre-partitioned file's output only has the one partition
+        let mut partition_lines = vec![0; nfolders];
+        let mut partition_bytes = vec![0; nfolders];
+        for (shard, partition) in shard_stats.iter().zip(&chunk_partitions) {
+            for (i, (lines, bytes)) in shard
+                .partition_lines
+                .iter()
+                .zip(&shard.partition_bytes)
+                .enumerate()
+            {
+                partition_lines[partition.unwrap_or(i)] += lines;
+                partition_bytes[partition.unwrap_or(i)] += bytes;
+            }
+        }
+        let report = json!({
+            "nthreads": requested_nthreads,
+            "nchunks": chunks.len(),
+            "total_bytes_read": shard_stats.iter().map(|s| s.bytes).sum::<usize>(),
+            "total_lines_sent": partition_lines.iter().sum::<usize>(),
+            "per_partition_lines": partition_lines,
+            "per_partition_bytes": partition_bytes,
+            "per_partition_blocking_events": stats.lines_blocking,
+            "wall_time_seconds": start.elapsed().as_secs_f64(),
+            "mapper_cmd": mapper_cmd,
+            "folder_cmd": folder_cmd,
+        });
+        if let Err(e) = fs::write(path, format!("{}\n", report)) {
+            eprintln!("error: writing {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Live progress reporting to stderr.
+
+use std::fs::File;
+use std::io::{self, IsTerminal, Seek, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+/// Counters of mapper output sent to each folder, shared between the
+/// mapper output threads and the reporter.
+#[derive(Debug)]
+pub struct Stats {
+    pub lines_sent: Vec<usize>,
+    pub lines_blocking: Vec<usize>,
+    pub bytes_sent: usize,
+    // only counted for progress bars, which show actual lines
+    pub lines_mapped: usize,
+    pub lines_folded: usize,
+}
+
+impl Stats {
+    pub fn new(npartitions: usize) -> Self {
+        Self {
+            lines_sent: vec![0; npartitions],
+            lines_blocking: vec![0; npartitions],
+            bytes_sent: 0,
+            lines_mapped: 0,
+            lines_folded: 0,
+        }
+    }
+}
+
+/// Tracks how far a mapper has read into its chunk, through a duplicate
+/// of its stdin file handle, which shares the same file offset.
+pub struct ChunkCursor {
+    file: File,
+    start: u64,
+    nbytes: u64,
+}
+
+impl ChunkCursor {
+    /// Expects `stdin` to be pre-seeked to the start of the chunk.
+    pub fn new(stdin: &File, nbytes: usize) -> Self {
+        let mut file = stdin.try_clone().expect("duplicate mapper stdin");
+        let start = file.stream_position().expect("seek");
+        Self {
+            file,
+            start,
+            nbytes: nbytes as u64,
+        }
+    }
+
+    fn nread(&mut self) -> u64 {
+        // read-ahead may leave the offset past the chunk
+        let offset = self.file.stream_position().unwrap_or(self.start);
+        offset.saturating_sub(self.start).min(self.nbytes)
+    }
+}
+
+/// Spawns a thread printing progress every second, overwriting the
+/// previous report, until `done` hangs up.
+///
+/// Only uncompressed input can be tracked; compressed chunks don't count
+/// towards the read total or the ETA.
+pub fn spawn_reporter(
+    stats: Arc<Mutex<Stats>>,
+    mut cursors: Vec<ChunkCursor>,
+    done: Receiver<()>,
+) -> JoinHandle<()> {
+    let total: u64 = cursors.iter().map(|c| c.nbytes).sum();
+    let start = Instant::now();
+    thread::spawn(move || {
+        let mut width = 0;
+        while let Err(RecvTimeoutError::Timeout) = done.recv_timeout(Duration::from_secs(1)) {
+            let nread: u64 = cursors.iter_mut().map(ChunkCursor::nread).sum();
+            let elapsed = start.elapsed().as_secs_f64();
+            let throughput = nread as f64 / elapsed;
+            let eta = if throughput > 0.0 {
+                format_duration((total - nread) as f64 / throughput)
+            } else {
+                "?".to_owned()
+            };
+            let report = {
+                let stats = stats.lock().unwrap();
+                format!(
+                    "read {}/{} ({}/s, ETA {}) sent {} ({}) block {:?}",
+                    format_bytes(nread as f64),
+                    format_bytes(total as f64),
+                    format_bytes(throughput),
+                    eta,
+                    stats.lines_sent.iter().sum::<usize>(),
+                    format_bytes(stats.bytes_sent as f64),
+                    stats.lines_blocking,
+                )
+            };
+            // pad to erase any leftovers of a longer previous report
+            eprint!("\r{:width$}", report, width = width);
+            width = report.len();
+            std::io::stderr().flush().expect("flush stderr");
+        }
+        if width > 0 {
+            eprintln!();
+        }
+    })
+}
+
+/// Spawns a thread redrawing progress bars on stderr until `done` hangs
+/// up: one per input file, of the bytes read from its chunks among
+/// `cursors`, and two of the lines mappers sent and folders were fed.
+///
+/// If stderr isn't a terminal, nothing is drawn, and no thread spawned.
+pub fn spawn_bars(
+    stats: Arc<Mutex<Stats>>,
+    cursors: Vec<(PathBuf, ChunkCursor)>,
+    done: Receiver<()>,
+) -> Option<JoinHandle<()>> {
+    if !io::stderr().is_terminal() {
+        return None;
+    }
+    let file_style = ProgressStyle::with_template(
+        "{prefix} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec}, ETA {eta})",
+    )
+    .expect("file bar template")
+    .progress_chars("=> ");
+    let line_style = ProgressStyle::with_template("{prefix} {human_pos} lines in {elapsed}")
+        .expect("line bar template");
+    let bars = MultiProgress::new();
+    let mut files: Vec<(PathBuf, Vec<ChunkCursor>)> = Vec::new();
+    for (path, cursor) in cursors {
+        match files.iter_mut().find(|(file, _)| *file == path) {
+            Some((_, file_cursors)) => file_cursors.push(cursor),
+            None => files.push((path, vec![cursor])),
+        }
+    }
+    let mut files: Vec<_> = files
+        .into_iter()
+        .map(|(path, cursors)| {
+            let total = cursors.iter().map(|c| c.nbytes).sum();
+            let bar = bars.add(ProgressBar::new(total).with_style(file_style.clone()));
+            bar.set_prefix(path.display().to_string());
+            (bar, cursors)
+        })
+        .collect();
+    let stage_bar = |name: &'static str| {
+        let bar = bars.add(ProgressBar::no_length().with_style(line_style.clone()));
+        bar.set_prefix(name);
+        bar
+    };
+    let mapped = stage_bar("mapped");
+    let folded = stage_bar("folded");
+    Some(thread::spawn(move || {
+        let mut update = || {
+            for (bar, cursors) in &mut files {
+                bar.set_position(cursors.iter_mut().map(ChunkCursor::nread).sum());
+            }
+            let stats = stats.lock().unwrap();
+            mapped.set_position(stats.lines_mapped as u64);
+            folded.set_position(stats.lines_folded as u64);
+        };
+        while let Err(RecvTimeoutError::Timeout) = done.recv_timeout(Duration::from_millis(100)) {
+            update();
+        }
+        // show where everything ended up
+        update();
+        for (bar, _) in &files {
+            bar.finish();
+        }
+        mapped.finish();
+        folded.finish();
+    }))
+}
+
+fn format_bytes(nbytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut scaled = nbytes;
+    let mut unit = 0;
+    while scaled >= 1024.0 && unit + 1 < UNITS.len() {
+        scaled /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", scaled, UNITS[unit])
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
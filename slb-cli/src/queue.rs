@@ -0,0 +1,57 @@
+//! Monitoring of the queues carrying mapper output to folders.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Tracks the depth of each folder's queue, and its high-water mark.
+///
+/// Depths count buffers from the moment a mapper starts sending them, so
+/// mappers blocked on a full queue count towards its depth.
+pub struct QueueDepths {
+    capacity: usize,
+    depths: Vec<AtomicUsize>,
+    max_depths: Vec<AtomicUsize>,
+    warned: AtomicBool,
+}
+
+impl QueueDepths {
+    pub fn new(nqueues: usize, capacity: usize) -> Self {
+        Self {
+            capacity,
+            depths: (0..nqueues).map(|_| AtomicUsize::new(0)).collect(),
+            max_depths: (0..nqueues).map(|_| AtomicUsize::new(0)).collect(),
+            warned: AtomicBool::new(false),
+        }
+    }
+
+    /// Records that a buffer is about to be sent to queue `ix`, warning
+    /// once if this leaves every queue full.
+    pub fn enqueue(&self, ix: usize) {
+        let depth = self.depths[ix].fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_depths[ix].fetch_max(depth, Ordering::SeqCst);
+        if depth >= self.capacity
+            && self
+                .depths
+                .iter()
+                .all(|depth| depth.load(Ordering::SeqCst) >= self.capacity)
+            && !self.warned.swap(true, Ordering::SeqCst)
+        {
+            eprintln!(
+                "warning: all folder queues are full, consider increasing \
+                 --bufsize or --queuesize"
+            );
+        }
+    }
+
+    /// Records that a buffer left queue `ix`, or was never sent after all.
+    pub fn dequeue(&self, ix: usize) {
+        self.depths[ix].fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Returns the maximum depth each queue reached.
+    pub fn max_depths(&self) -> Vec<usize> {
+        self.max_depths
+            .iter()
+            .map(|depth| depth.load(Ordering::SeqCst))
+            .collect()
+    }
+}
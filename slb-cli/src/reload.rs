@@ -0,0 +1,54 @@
+//! Swapping in new folder commands while running, see `--hot-reload`.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use signal_hook::consts::SIGUSR1;
+use signal_hook::iterator::Signals;
+
+/// The latest folder command, along with how many times it's been
+/// reloaded.
+pub struct Reloader {
+    latest: Mutex<(usize, String)>,
+}
+
+impl Reloader {
+    /// Starts reloading the folder command from `config` on every SIGUSR1,
+    /// leaving the current one, `folder_cmd`, in place if it can't be read.
+    pub fn spawn(config: PathBuf, folder_cmd: &str) -> io::Result<Arc<Self>> {
+        let reloader = Arc::new(Self {
+            latest: Mutex::new((0, folder_cmd.to_owned())),
+        });
+        let mut signals = Signals::new([SIGUSR1])?;
+        let handle = Arc::clone(&reloader);
+        // lives as long as the process, since signals may keep arriving
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                match fs::read_to_string(&config) {
+                    Ok(cmd) => {
+                        let mut latest = handle.latest.lock().unwrap();
+                        latest.0 += 1;
+                        latest.1 = cmd.trim_end_matches('\n').to_owned();
+                        eprintln!("reloading folders with: {}", latest.1);
+                    }
+                    Err(e) => eprintln!(
+                        "warning: not reloading folders, reading {}: {}",
+                        config.display(),
+                        e
+                    ),
+                }
+            }
+        });
+        Ok(reloader)
+    }
+
+    /// Returns the latest folder command and its generation, if it's been
+    /// reloaded since `generation`.
+    pub fn newer_than(&self, generation: usize) -> Option<(usize, String)> {
+        let latest = self.latest.lock().unwrap();
+        (latest.0 != generation).then(|| latest.clone())
+    }
+}
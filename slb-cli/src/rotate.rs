@@ -0,0 +1,118 @@
+//! Splitting of large output files.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use bstr::ByteSlice;
+use bytes::Bytes;
+
+/// How big an output file may get before [`CountingWriter`] moves on to
+/// the next one.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OutputLimit {
+    pub max_bytes: Option<usize>,
+    pub max_lines: Option<usize>,
+}
+
+/// A writer of lines into `path`, then `path_1`, `path_2`, and so on,
+/// which moves on to the next file before a line that would take the
+/// current one over its [`OutputLimit`]. Only a single line longer than
+/// the limit makes a file any bigger.
+///
+/// Each file starts with the same header, which counts towards its bytes
+/// but not its lines.
+///
+/// Lines split across writes are buffered until they end.
+pub struct CountingWriter {
+    file: File,
+    path: PathBuf,
+    rotated_paths: Vec<PathBuf>,
+    limit: OutputLimit,
+    terminator: u8,
+    header: Bytes,
+    nbytes: usize,
+    nlines: usize,
+    partial: Vec<u8>,
+}
+
+impl CountingWriter {
+    /// Starts writing into `file`, already opened at `path`.
+    pub fn new(file: File, path: &Path, limit: OutputLimit, terminator: u8, header: Bytes) -> Self {
+        Self {
+            file,
+            path: path.to_owned(),
+            rotated_paths: Vec::new(),
+            limit,
+            terminator,
+            header,
+            nbytes: 0,
+            nlines: 0,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Writes out an unterminated last line, if any, returning the paths
+    /// of all the files moved on to, in order.
+    pub fn finish(mut self) -> io::Result<Vec<PathBuf>> {
+        if !self.partial.is_empty() {
+            let line = mem::take(&mut self.partial);
+            self.write_line(&line)?;
+        } else if self.nbytes == 0 {
+            // even empty output gets its header
+            self.file.write_all(&self.header)?;
+        }
+        self.file.flush()?;
+        Ok(self.rotated_paths)
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let full = self
+            .limit
+            .max_bytes
+            .is_some_and(|max| self.nbytes + line.len() > max)
+            || self.limit.max_lines.is_some_and(|max| self.nlines >= max);
+        if full && self.nbytes > 0 {
+            let mut path = OsString::from(&self.path);
+            path.push(format!("_{}", self.rotated_paths.len() + 1));
+            let path = PathBuf::from(path);
+            self.file = File::create(&path)?;
+            self.rotated_paths.push(path);
+            self.nbytes = 0;
+            self.nlines = 0;
+        }
+        if self.nbytes == 0 {
+            self.file.write_all(&self.header)?;
+            self.nbytes = self.header.len();
+        }
+        self.file.write_all(line)?;
+        self.nbytes += line.len();
+        self.nlines += 1;
+        Ok(())
+    }
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while let Some(end) = rest.find_byte(self.terminator) {
+            let (line, tail) = rest.split_at(end + 1);
+            if self.partial.is_empty() {
+                self.write_line(line)?;
+            } else {
+                self.partial.extend_from_slice(line);
+                let line = mem::take(&mut self.partial);
+                self.write_line(&line)?;
+            }
+            rest = tail;
+        }
+        self.partial.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -0,0 +1,103 @@
+//! Persistent folders, served over Unix domain sockets.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::ChildEnv;
+
+/// How long a newly started server has to start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often to retry connecting to a starting server.
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Connects to the folder server listening on `socket`, first starting
+/// one in the background if there's none, which runs `folder_cmd` with
+/// its output appended to `output`.
+///
+/// The server is `slb` itself, re-run with the hidden `--serve-folder`
+/// flag in its own process group, so it outlives this run and its
+/// interrupts.
+pub fn connect(
+    socket: &Path,
+    output: &Path,
+    folder_cmd: &str,
+    child_env: &ChildEnv,
+) -> io::Result<UnixStream> {
+    match UnixStream::connect(socket) {
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => (),
+        result => return result,
+    }
+    let mut cmd = Command::new(env::current_exe()?);
+    cmd.arg("--folder")
+        .arg(folder_cmd)
+        .arg("--shell")
+        .arg(&child_env.shell)
+        .arg("--serve-folder")
+        .arg(socket)
+        .arg("--serve-output")
+        .arg(output);
+    for (key, value) in &child_env.vars {
+        cmd.arg("--env").arg(format!("{}={}", key, value));
+    }
+    if child_env.clear {
+        cmd.arg("--clear-env");
+    }
+    // never waited on, it's reaped once this process exits
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        match UnixStream::connect(socket) {
+            Err(_) if Instant::now() < deadline => thread::sleep(RETRY_INTERVAL),
+            result => return result,
+        }
+    }
+}
+
+/// Runs `folder_cmd` for as long as it keeps reading, appending its output
+/// to `output` and feeding it everything sent over each connection to
+/// `socket`, one connection at a time.
+///
+/// Once the server is killed, the folder sees the end of its input.
+pub fn serve(
+    socket: &Path,
+    output: &Path,
+    folder_cmd: &str,
+    child_env: &ChildEnv,
+) -> io::Result<()> {
+    // a dead server leaves its socket file behind, which would fail the bind
+    match fs::remove_file(socket) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    let listener = UnixListener::bind(socket)?;
+    let file = OpenOptions::new().create(true).append(true).open(output)?;
+    let mut folder = child_env
+        .shell()
+        .arg("-c")
+        .arg(folder_cmd)
+        .stdin(Stdio::piped())
+        .stdout(file)
+        .spawn()?;
+    let mut stdin = folder.stdin.take().expect("folder stdin");
+    let result = listener
+        .incoming()
+        .try_for_each(|conn| io::copy(&mut conn?, &mut stdin).map(drop));
+    drop(stdin);
+    fs::remove_file(socket)?;
+    folder.wait()?;
+    match result {
+        // the folder hung up
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
@@ -0,0 +1,71 @@
+//! Killing of hung child processes.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use libc::{c_int, SIGINT, SIGKILL, SIGTERM};
+
+/// How long a timed-out child has to exit after SIGTERM before SIGKILL.
+const GRACE_PERIOD: Duration = Duration::from_secs(5);
+/// How often the interrupt flag is checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Watches a child leading its own process group, which lets the whole
+/// bash command line be killed, rather than just the shell.
+///
+/// Since such children don't receive the terminal's SIGINT, the watchdog
+/// forwards it once `shutdown` is set.
+pub struct Watchdog {
+    done: Sender<()>,
+    handle: JoinHandle<bool>,
+}
+
+impl Watchdog {
+    /// Starts watching the process group of `pid`, sending it SIGTERM
+    /// once `timeout` has passed, and SIGKILL after a grace period.
+    pub fn spawn(pid: u32, timeout: Duration, shutdown: Arc<AtomicBool>) -> Self {
+        let (done, rx) = mpsc::channel();
+        let deadline = Instant::now() + timeout;
+        let handle = thread::spawn(move || {
+            let mut interrupted = false;
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                match rx.recv_timeout(POLL_INTERVAL.min(deadline - now)) {
+                    Err(RecvTimeoutError::Timeout) => (),
+                    _ => return false,
+                }
+                if !interrupted && shutdown.load(Ordering::SeqCst) {
+                    signal(pid, SIGINT);
+                    interrupted = true;
+                }
+            }
+            signal(pid, SIGTERM);
+            if let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(GRACE_PERIOD) {
+                signal(pid, SIGKILL);
+            }
+            true
+        });
+        Self { done, handle }
+    }
+
+    /// Stops watching once the child has been waited on, returning
+    /// whether it was killed for timing out.
+    pub fn finish(self) -> bool {
+        drop(self.done);
+        self.handle.join().expect("watchdog join")
+    }
+}
+
+fn signal(pgid: u32, signal: c_int) {
+    // Safety: kill(2) has no memory effects. The group may be gone
+    // already, in which case this fails harmlessly.
+    unsafe {
+        libc::kill(-(pgid as libc::pid_t), signal);
+    }
+}
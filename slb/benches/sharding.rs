@@ -0,0 +1,81 @@
+//! Micro-benchmarks of sharding and chunking, run with `cargo bench`.
+
+use std::io::{BufReader, Cursor, Write};
+
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use slb::fileblocks;
+use slb::sharder::{self, HashFn, Key, ShardMode, ShardOptions};
+
+/// Lines like `key_000042 value_000042`, up to about `nbytes` in all.
+fn synthetic_lines(nbytes: usize) -> Vec<u8> {
+    let mut lines = Vec::with_capacity(nbytes + 32);
+    let mut i = 0;
+    while lines.len() < nbytes {
+        writeln!(lines, "key_{:06} value_{:06}", i, i).unwrap();
+        i += 1;
+    }
+    lines
+}
+
+fn bench_shard(c: &mut Criterion) {
+    let input = synthetic_lines(10 * 1024 * 1024);
+    let mut group = c.benchmark_group("shard");
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.sample_size(20);
+    for &npartitions in &[1, 16, 256] {
+        for &bufsize in &[4 * 1024, 64 * 1024] {
+            let id = BenchmarkId::new(format!("{}KB", bufsize / 1024), npartitions);
+            group.bench_with_input(id, &(npartitions, bufsize), |b, &(npartitions, bufsize)| {
+                b.iter(|| {
+                    sharder::shard(
+                        BufReader::new(Cursor::new(&input)),
+                        npartitions,
+                        bufsize,
+                        &Key::default(),
+                        ShardMode::default(),
+                        ShardOptions::default(),
+                        |ix, buf: Bytes| {
+                            black_box((ix, buf));
+                        },
+                    )
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_chunkify(c: &mut Criterion) {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(&synthetic_lines(100 * 1024 * 1024)).unwrap();
+    file.flush().unwrap();
+    c.bench_function("chunkify", |b| {
+        b.iter(|| fileblocks::chunkify(file.path(), black_box(64), 16 * 1024, None).unwrap())
+    });
+}
+
+fn bench_hash(c: &mut Criterion) {
+    let input = synthetic_lines(24 * 1_000_000);
+    let keys: Vec<_> = input
+        .split(|&b| b == b'\n')
+        .filter_map(|line| line.split(|&b| b == b' ').next())
+        .collect();
+    let mut group = c.benchmark_group("hash");
+    group.throughput(Throughput::Elements(keys.len() as u64));
+    group.sample_size(20);
+    for hasher in [HashFn::Fnv, HashFn::XxHash, HashFn::SipHash] {
+        group.bench_function(hasher.to_string(), |b| {
+            b.iter(|| {
+                keys.iter()
+                    .map(|key| hasher.hash(key) % 16)
+                    .fold(0, u64::wrapping_add)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_shard, bench_chunkify, bench_hash);
+criterion_main!(benches);
@@ -0,0 +1,33 @@
+//! Chunks arbitrary file contents, checking that dumping every chunk in
+//! order gives back exactly the file. Run with `cargo fuzz run chunkify`
+//! from `slb/`.
+
+#![no_main]
+
+use std::io::Write;
+
+use libfuzzer_sys::fuzz_target;
+
+use slb::fileblocks::{self, Compression};
+
+fuzz_target!(|data: &[u8]| {
+    // the first two bytes pick the chunking, the rest is the file
+    let (max_chunks, min_size, contents) = match data {
+        [a, b, contents @ ..] => (*a as usize % 16 + 1, *b as usize + 1, contents),
+        _ => return,
+    };
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(contents).unwrap();
+    file.flush().unwrap();
+
+    // explicitly uncompressed, lest contents look like gzip or zstd
+    let chunks =
+        fileblocks::chunkify(file.path(), max_chunks, min_size, Some(Compression::None)).unwrap();
+    assert!(chunks.len() <= max_chunks);
+    let mut dumped = Vec::with_capacity(contents.len());
+    for chunk in &chunks {
+        assert!(chunk.nbytes() > 0, "empty chunk {:?}", chunk);
+        chunk.dump(&mut dumped, false).unwrap();
+    }
+    assert_eq!(dumped, contents);
+});
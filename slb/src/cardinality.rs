@@ -0,0 +1,76 @@
+//! Approximate counting of distinct keys.
+
+use std::fmt;
+
+/// A HyperLogLog sketch, estimating the number of distinct keys inserted
+/// into it to within about 1.6% (one standard error) in 4 KB, however
+/// many there are.
+///
+/// Sketches of disjoint inputs can be [merged](HyperLogLog::merge) to
+/// estimate the distinct keys across all of them.
+#[derive(Clone, PartialEq, Eq)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    const PRECISION: u32 = 12;
+
+    /// Returns an empty sketch.
+    pub fn new() -> Self {
+        Self {
+            registers: vec![0; 1 << Self::PRECISION],
+        }
+    }
+
+    /// Adds a key to the sketch.
+    pub fn insert(&mut self, key: &[u8]) {
+        let hash = xxhash_rust::xxh64::xxh64(key, 0);
+        let index = (hash >> (64 - Self::PRECISION)) as usize;
+        // the rest of the hash, with a sentinel bit should it be all zero
+        let rest = (hash << Self::PRECISION) | (1 << (Self::PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        self.registers[index] = self.registers[index].max(rank);
+    }
+
+    /// Folds another sketch into this one, as if its keys had been
+    /// inserted here too.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (mine, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *mine = (*mine).max(*theirs);
+        }
+    }
+
+    /// Estimates the number of distinct keys inserted.
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|&rank| 2f64.powi(-(rank as i32)))
+            .sum();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&rank| rank == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            // linear counting is more accurate for small cardinalities
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        }
+    }
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for HyperLogLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HyperLogLog")
+            .field("estimate", &self.estimate())
+            .finish()
+    }
+}
@@ -1,91 +1,703 @@
 //! Utilities for converting files into blocks.
 
 use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
 use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, Read, Write};
 use std::io::{BufRead, BufReader, ErrorKind};
 use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::FileTypeExt;
+use std::str::FromStr;
 
 use std::path::Path;
 use std::path::PathBuf;
 
 use bstr::io::BufReadExt;
+use flate2::read::MultiGzDecoder;
 
 use memchr;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh64::Xxh64;
 
 const BUFFER_SIZE: usize = 16 * 1024;
+#[cfg(feature = "mmap")]
+const MMAP_THRESHOLD: usize = 1024 * 1024;
+// Bytes read from the start of a chunk to estimate its line count.
+const LINE_SAMPLE_SIZE: usize = 4 * 1024;
 
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const ZSTD_SKIPPABLE_MAGIC: u32 = 0x184d_2a5e;
+const ZSTD_SEEKABLE_MAGIC: u32 = 0x8f92_eab1;
+const ZSTD_SEEKABLE_FOOTER_SIZE: u64 = 9;
+
+/// The compression format of an input file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Compression {
+    /// Plain text, which can be split at arbitrary byte offsets.
+    None,
+    /// Gzip, which can't be seeked and so is always read as one chunk.
+    Gzip,
+    /// Zstandard. Files in the seekable format, which append a table of
+    /// independently-compressed frames, are split on frame boundaries;
+    /// other files are read as one chunk.
+    Zstd,
+}
+
+impl Compression {
+    /// Guesses the compression of the file at `path` from its extension
+    /// (`.gz` or `.zst`), falling back to its leading magic bytes.
+    pub fn detect(path: &Path) -> Compression {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => return Compression::Gzip,
+            Some("zst") => return Compression::Zstd,
+            _ => (),
+        }
+        let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+        File::open(path)
+            .expect("file available")
+            .take(ZSTD_MAGIC.len() as u64)
+            .read_to_end(&mut magic)
+            .expect("read");
+        if magic.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("unknown compression {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Why a [`FileChunk`] is inconsistent with its file, see
+/// [`FileChunk::validate`].
 #[derive(Debug)]
+pub enum ChunkError {
+    /// The file can't be opened or read.
+    FileNotFound(io::Error),
+    /// The chunk's `start` is past its `stop`, or its `stop` is past the
+    /// end of the file.
+    InvalidRange {
+        start: usize,
+        stop: usize,
+        file_size: usize,
+    },
+    /// The byte before the chunk's start offset, given here, doesn't end
+    /// a line.
+    StartNotAligned(usize),
+    /// The byte before the chunk's stop offset, given here, doesn't end a
+    /// line, and it isn't the end of the file either.
+    StopNotAligned(usize),
+    /// The chunk's bytes don't match its recorded checksum.
+    ChecksumMismatch { expected: u64, actual: u64 },
+    /// The file's size, given here, doesn't fit in a `usize`, as for files
+    /// over 4 GB on 32-bit targets. Every size fits on 64-bit ones.
+    FileTooLarge(u64),
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkError::FileNotFound(e) => write!(f, "chunk file unreadable: {}", e),
+            ChunkError::InvalidRange {
+                start,
+                stop,
+                file_size,
+            } => write!(
+                f,
+                "chunk range {}..{} invalid for file of size {}",
+                start, stop, file_size
+            ),
+            ChunkError::StartNotAligned(start) => {
+                write!(f, "chunk start {} is not at a line boundary", start)
+            }
+            ChunkError::StopNotAligned(stop) => {
+                write!(f, "chunk stop {} is not at a line boundary", stop)
+            }
+            ChunkError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "chunk checksum {:016x} doesn't match recorded {:016x}",
+                actual, expected
+            ),
+            ChunkError::FileTooLarge(size) => {
+                write!(f, "file of {} bytes is too large for this platform", size)
+            }
+        }
+    }
+}
+
+impl Error for ChunkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ChunkError::FileNotFound(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A newline-aligned range of lines within a file, or a null-aligned
+/// range of records for chunks from [`chunkify_nullsep`], in which case
+/// "lines" below means null-terminated records.
+///
+/// For compressed files, `start` and `stop` are offsets into the
+/// decompressed stream, and a `stop` of `usize::MAX` reads until EOF.
+///
+/// With the `serde` feature, chunks can be serialized, e.g. to JSON, and
+/// handed to another machine to [`dump`](FileChunk::dump), so long as the
+/// path resolves to the same file there, as on a shared filesystem.
+/// Attach a checksum with [`FileChunk::with_checksum`] to have the
+/// receiver [`verify`](FileChunk::verify_checksum) it sees the same bytes.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FileChunk {
     path: PathBuf,
     start: usize,
     stop: usize,
+    compression: Compression,
+    // Compressed offset to start decoding from, and the decompressed
+    // offset it corresponds to; both `start` for uncompressed chunks.
+    decode_from: (u64, usize),
+    terminator: u8,
+    // xxh64 of the chunk's decompressed bytes, if recorded.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    checksum: Option<u64>,
 }
 
 impl FileChunk {
     /// Prepare a pre-seeked file for this chunk.
+    ///
+    /// For compressed chunks this is the raw file, seeked to wherever
+    /// decoding starts; prefer [`FileChunk::reader`] for those.
+    ///
+    /// In debug builds, this panics if the chunk doesn't
+    /// [validate](FileChunk::validate).
     pub fn file(&self) -> File {
+        if self.is_stream() {
+            // read exactly once, from the start
+            return File::open(&self.path).expect("file available");
+        }
+        #[cfg(debug_assertions)]
+        {
+            if let Err(e) = self.validate() {
+                panic!("{}: {}", self.path.display(), e);
+            }
+        }
         let mut file = File::open(&self.path).expect("file available");
-        file.seek(SeekFrom::Start(self.start.try_into().unwrap()))
+        file.seek(SeekFrom::Start(self.decode_from.0))
             .expect("seek");
         file
     }
 
+    /// Returns the bytes of an uncompressed chunk, read straight from its
+    /// pre-seeked [file](FileChunk::file) and limited to exactly the
+    /// chunk's range, e.g., for `io::copy(&mut chunk.byte_slice(), &mut w)`.
+    ///
+    /// Panics if the chunk is compressed.
+    pub fn byte_slice(&self) -> impl Read + Send {
+        assert_eq!(
+            self.compression,
+            Compression::None,
+            "only uncompressed chunks can be sliced"
+        );
+        self.file().take(self.nbytes() as u64)
+    }
+
+    /// Checks that the chunk is still consistent with its file, which
+    /// catches the file being modified since it was chunkified.
+    ///
+    /// The file must be readable and, for uncompressed chunks, the chunk
+    /// must lie within the file and start and stop on line boundaries.
+    /// The offsets of compressed chunks are into the decompressed stream,
+    /// so only their order is checked.
+    pub fn validate(&self) -> Result<(), ChunkError> {
+        let mut file = File::open(&self.path).map_err(ChunkError::FileNotFound)?;
+        let file_size = to_usize(file.metadata().map_err(ChunkError::FileNotFound)?.len())?;
+        let check_size = self.compression == Compression::None;
+        if self.start > self.stop || (check_size && self.stop > file_size) {
+            return Err(ChunkError::InvalidRange {
+                start: self.start,
+                stop: self.stop,
+                file_size,
+            });
+        }
+        if !check_size {
+            return Ok(());
+        }
+        let mut ends_line = |offset: usize| -> Result<bool, ChunkError> {
+            let mut byte = [0u8];
+            file.seek(SeekFrom::Start(offset as u64 - 1))
+                .and_then(|_| file.read_exact(&mut byte))
+                .map_err(ChunkError::FileNotFound)?;
+            Ok(byte[0] == self.terminator)
+        };
+        if self.start > 0 && !ends_line(self.start)? {
+            return Err(ChunkError::StartNotAligned(self.start));
+        }
+        if self.stop > self.start && self.stop < file_size && !ends_line(self.stop)? {
+            return Err(ChunkError::StopNotAligned(self.stop));
+        }
+        Ok(())
+    }
+
     /// Return the number of bytes to read for this chunk.
+    ///
+    /// This is exact for uncompressed chunks. For compressed ones it's an
+    /// estimate in decompressed bytes, saturating for unbounded chunks.
     pub fn nbytes(&self) -> usize {
         self.stop - self.start
     }
 
-    /// Iterates over just those lines the file chunk refers to.
-    pub fn dump<W: Write>(&self, mut w: W) {
-        let mut file = File::open(&self.path).expect("file available");
-        file.seek(SeekFrom::Start(self.start.try_into().unwrap()))
-            .expect("seek");
-        let reader = BufReader::with_capacity(BUFFER_SIZE.min(self.stop - self.start), file);
-        let mut current_byte = self.start;
-        let stop_byte = self.stop;
+    /// Whether the chunk's file is a named pipe or character device, which
+    /// can only be read once, start to finish, and so neither
+    /// [validated](FileChunk::validate) nor memory-mapped.
+    pub fn is_stream(&self) -> bool {
+        fs::metadata(&self.path).is_ok_and(|metadata| is_stream(&metadata))
+    }
 
-        reader
-            .for_byte_line_with_terminator(|line| {
-                if current_byte >= stop_byte {
-                    return Ok(false);
+    /// Return the path of the chunk's file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return the (decompressed) byte offsets `[start, stop)` of the
+    /// chunk, see [`FileChunk`].
+    pub fn range(&self) -> (usize, usize) {
+        (self.start, self.stop)
+    }
+
+    /// Return the compression format of the chunk's file.
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Return the checksum recorded by [`FileChunk::with_checksum`], if
+    /// any.
+    pub fn checksum(&self) -> Option<u64> {
+        self.checksum
+    }
+
+    /// Records a checksum of the chunk's current bytes, which requires
+    /// reading them all.
+    pub fn with_checksum(mut self) -> Self {
+        self.checksum = Some(self.compute_checksum());
+        self
+    }
+
+    /// Checks the chunk's bytes against its recorded checksum, reading
+    /// them all; chunks without one always pass.
+    pub fn verify_checksum(&self) -> Result<(), ChunkError> {
+        match self.checksum {
+            Some(expected) => {
+                let actual = self.compute_checksum();
+                if actual == expected {
+                    Ok(())
+                } else {
+                    Err(ChunkError::ChecksumMismatch { expected, actual })
                 }
-                assert!(
-                    current_byte < stop_byte + 1,
-                    "can only overshoot if non-newline split or eof with no newline"
-                );
-                current_byte += line.len();
-                w.write(line).expect("write");
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn compute_checksum(&self) -> u64 {
+        let mut hasher = Xxh64::new(0);
+        let mut reader = self.reader();
+        loop {
+            let buf = reader.fill_buf().expect("read");
+            if buf.is_empty() {
+                return hasher.digest();
+            }
+            let nread = buf.len();
+            hasher.update(buf);
+            reader.consume(nread);
+        }
+    }
+
+    /// Returns a reader over the decompressed bytes of just those lines
+    /// the file chunk refers to.
+    ///
+    /// The file is read lazily, a buffer at a time, so the chunk can be
+    /// streamed, e.g., into a child's stdin with [`io::copy`], without
+    /// holding all of it in memory.
+    pub fn reader(&self) -> ChunkReader {
+        let capacity = BUFFER_SIZE.min(self.nbytes());
+        let inner: Box<dyn BufRead + Send> = match self.compression {
+            Compression::None => Box::new(BufReader::with_capacity(capacity, self.byte_slice())),
+            Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(self.file()))),
+            Compression::Zstd => Box::new(BufReader::new(
+                zstd::Decoder::new(self.file()).expect("zstd decoder"),
+            )),
+        };
+        let mut reader = ChunkReader {
+            inner,
+            current_byte: self.decode_from.1,
+            stop_byte: self.stop,
+            at_line_start: true,
+            terminator: self.terminator,
+        };
+        if self.decode_from.1 < self.start {
+            // Decoding starts one frame early, so the byte just before
+            // `start` tells us whether `start` begins a line. If not, that
+            // line belongs to the previous chunk.
+            let skip = self.start - self.decode_from.1 - 1;
+            io::copy(&mut (&mut reader.inner).take(skip as u64), &mut io::sink()).expect("skip");
+            let mut prev = Vec::with_capacity(1);
+            (&mut reader.inner)
+                .take(1)
+                .read_to_end(&mut prev)
+                .expect("read");
+            reader.current_byte = self.start;
+            if prev != [self.terminator] {
+                reader.current_byte += read_until(self.terminator, &mut reader.inner);
+            }
+        }
+        reader
+    }
+
+    /// Memory-maps exactly the bytes of an uncompressed chunk, which lets
+    /// the OS prefetch pages, unlike reading through a buffer.
+    ///
+    /// Panics if the chunk is compressed.
+    #[cfg(feature = "mmap")]
+    pub fn mmap_bytes(&self) -> memmap2::Mmap {
+        assert_eq!(
+            self.compression,
+            Compression::None,
+            "only uncompressed chunks can be memory-mapped"
+        );
+        let file = File::open(&self.path).expect("file available");
+        // Safety: as everywhere in this module, the file is assumed to not
+        // be modified while its chunks are in use.
+        unsafe {
+            memmap2::MmapOptions::new()
+                .offset(self.decode_from.0)
+                .len(self.nbytes())
+                .map(&file)
+        }
+        .expect("mmap")
+    }
+
+    /// Writes out just those lines the file chunk refers to.
+    ///
+    /// These are copied verbatim, so if the file's last line has no
+    /// trailing newline, neither does the output of the chunk containing
+    /// it. With `mmap` and the `mmap` feature, uncompressed chunks of at
+    /// least 1MB are copied from a memory map, see [`FileChunk::mmap_bytes`].
+    #[cfg_attr(not(feature = "mmap"), allow(unused_variables))]
+    pub fn dump<W: Write>(&self, mut w: W, mmap: bool) -> io::Result<()> {
+        #[cfg(feature = "mmap")]
+        if mmap
+            && self.compression == Compression::None
+            && !self.is_stream()
+            && self.nbytes() >= MMAP_THRESHOLD
+        {
+            return w.write_all(&self.mmap_bytes());
+        }
+        if self.compression == Compression::None {
+            return io::copy(&mut self.byte_slice(), &mut w).map(drop);
+        }
+        io::copy(&mut self.reader(), &mut w).map(drop)
+    }
+
+    /// Drops up to `n` lines from the front of the chunk, writing them to
+    /// `w`, and returns how many it had; fewer than `n` leaves the chunk
+    /// empty. This skips, e.g., a header, which may span several chunks.
+    ///
+    /// Any recorded checksum is dropped along with the lines. Lines can't
+    /// be skipped in [streams](FileChunk::is_stream), which can only be
+    /// read once, so that fails with [`ErrorKind::Unsupported`].
+    pub fn skip_lines<W: Write>(&mut self, n: usize, mut w: W) -> io::Result<usize> {
+        if n == 0 {
+            return Ok(0);
+        }
+        if self.is_stream() {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "can't skip lines of a stream",
+            ));
+        }
+        let mut skipped = 0;
+        let mut nbytes = 0;
+        self.reader()
+            .for_byte_record_with_terminator(self.terminator, |line| {
+                w.write_all(line)?;
+                skipped += 1;
+                nbytes += line.len();
+                Ok(skipped < n)
+            })?;
+        // The last skipped line ends right before the new start, so it
+        // stays aligned even for compressed chunks.
+        self.start = (self.start + nbytes).min(self.stop);
+        if self.compression == Compression::None {
+            self.decode_from = (self.start as u64, self.start);
+        }
+        self.checksum = None;
+        Ok(skipped)
+    }
+
+    /// Counts just those lines the file chunk refers to, buffering the
+    /// file like [`FileChunk::reader`] but without copying lines out.
+    ///
+    /// An unterminated last line still counts.
+    pub fn line_count(&self) -> usize {
+        let mut count = 0;
+        self.reader()
+            .for_byte_record_with_terminator(self.terminator, |_| {
+                count += 1;
                 Ok(true)
             })
             .expect("read");
+        count
+    }
+
+    /// Estimates the number of lines in the chunk from the average length
+    /// of those in its first few KB, which is much cheaper than a full
+    /// [`FileChunk::line_count`] for big chunks. Small chunks are counted
+    /// exactly.
+    ///
+    /// Returns `None` for [streams](FileChunk::is_stream), unbounded
+    /// compressed chunks, and chunks that don't
+    /// [validate](FileChunk::validate).
+    pub fn estimated_line_count(&self) -> Option<usize> {
+        if self.stop == usize::MAX || self.is_stream() || self.validate().is_err() {
+            return None;
+        }
+        let mut sample = Vec::with_capacity(LINE_SAMPLE_SIZE.min(self.nbytes()));
+        self.reader()
+            .take(LINE_SAMPLE_SIZE as u64)
+            .read_to_end(&mut sample)
+            .ok()?;
+        let nlines = memchr::memchr_iter(self.terminator, &sample).count();
+        if sample.len() < LINE_SAMPLE_SIZE {
+            let unterminated = sample.last().is_some_and(|&b| b != self.terminator);
+            return Some(nlines + unterminated as usize);
+        }
+        // a line longer than the sample is at least one line
+        Some(self.nbytes() * nlines.max(1) / sample.len())
+    }
+
+    /// Lazily iterates over just those lines the file chunk refers to, as
+    /// owned newline-terminated byte vectors, except that the file's last
+    /// line is yielded as-is if it lacks a trailing newline.
+    pub fn lines(&self) -> Lines {
+        Lines {
+            reader: self.reader(),
+        }
+    }
+}
+
+/// Shows the chunk as `<path>[<start>..<stop>] (~<lines> lines)`, with the
+/// line count [estimated](FileChunk::estimated_line_count), or just
+/// `<path>[<start>..EOF]` for chunks read to EOF.
+impl fmt::Display for FileChunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}..", self.path.display(), self.start)?;
+        if self.stop == usize::MAX {
+            return f.write_str("EOF]");
+        }
+        write!(f, "{}]", self.stop)?;
+        match self.estimated_line_count() {
+            Some(nlines) => write!(f, " (~{} lines)", nlines),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Includes the modification time of the chunk's file, if available, to
+/// help tell whether it changed since it was chunkified.
+impl fmt::Debug for FileChunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let modified = fs::metadata(&self.path).and_then(|metadata| metadata.modified());
+        f.debug_struct("FileChunk")
+            .field("path", &self.path)
+            .field("start", &self.start)
+            .field("stop", &self.stop)
+            .field("compression", &self.compression)
+            .field("decode_from", &self.decode_from)
+            .field("terminator", &self.terminator)
+            .field("checksum", &self.checksum)
+            .field("modified", &modified.ok())
+            .finish()
+    }
+}
+
+/// Reader over the lines of a [`FileChunk`], see [`FileChunk::reader`].
+///
+/// This yields every line starting before the chunk's stop offset,
+/// including the remainder of the last line, if the stop offset does not
+/// fall on a line boundary.
+pub struct ChunkReader {
+    inner: Box<dyn BufRead + Send>,
+    current_byte: usize,
+    stop_byte: usize,
+    at_line_start: bool,
+    terminator: u8,
+}
+
+impl fmt::Debug for ChunkReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChunkReader")
+            .field("current_byte", &self.current_byte)
+            .field("stop_byte", &self.stop_byte)
+            .field("at_line_start", &self.at_line_start)
+            .finish()
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let nread = available.len().min(buf.len());
+        buf[..nread].copy_from_slice(&available[..nread]);
+        self.consume(nread);
+        Ok(nread)
+    }
+}
+
+impl BufRead for ChunkReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let remaining = self.stop_byte.saturating_sub(self.current_byte);
+        if remaining == 0 && self.at_line_start {
+            return Ok(&[]);
+        }
+        let available = self.inner.fill_buf()?;
+        let len = if remaining > 0 {
+            available.len().min(remaining)
+        } else {
+            memchr::memchr(self.terminator, available).map_or(available.len(), |i| i + 1)
+        };
+        Ok(&available[..len])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if amt == 0 {
+            return;
+        }
+        // The inner buffer is already filled, so this doesn't read.
+        let last = self.inner.fill_buf().expect("buffered")[amt - 1];
+        self.inner.consume(amt);
+        self.current_byte += amt;
+        self.at_line_start = last == self.terminator;
+    }
+}
+
+/// Iterator over the lines of a [`FileChunk`], see [`FileChunk::lines`].
+///
+/// The underlying file is closed when this is dropped.
+#[derive(Debug)]
+pub struct Lines {
+    reader: ChunkReader,
+}
+
+impl Iterator for Lines {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = Vec::new();
+        let terminator = self.reader.terminator;
+        let nread = self.reader.read_until(terminator, &mut line).expect("read");
+        if nread == 0 {
+            return None;
+        }
+        Some(line)
     }
 }
 
 /// Uses up to `max_chunks + paths.len()` chunks to chunkify multiple files.
-pub fn chunkify_multiple(paths: &[PathBuf], max_chunks: usize, min_size: usize) -> Vec<FileChunk> {
+///
+/// The `max_chunks` budget is divided among files in proportion to their
+/// sizes, though every non-empty file gets at least one chunk, and files
+/// are still split into chunks of roughly at least `min_size`, so small
+/// files always get exactly one.
+///
+/// `compression` applies to every file, and this fails like
+/// [`chunkify`] if any file does.
+pub fn chunkify_multiple(
+    paths: &[PathBuf],
+    max_chunks: usize,
+    min_size: usize,
+    compression: Option<Compression>,
+) -> Result<Vec<FileChunk>, ChunkError> {
+    chunkify_multiple_records(paths, max_chunks, min_size, compression, b'\n')
+}
+
+/// Like [`chunkify_multiple`], but with null-aligned chunks, see
+/// [`chunkify_nullsep`].
+pub fn chunkify_multiple_nullsep(
+    paths: &[PathBuf],
+    max_chunks: usize,
+    min_size: usize,
+    compression: Option<Compression>,
+) -> Result<Vec<FileChunk>, ChunkError> {
+    chunkify_multiple_records(paths, max_chunks, min_size, compression, b'\0')
+}
+
+fn chunkify_multiple_records(
+    paths: &[PathBuf],
+    max_chunks: usize,
+    min_size: usize,
+    compression: Option<Compression>,
+    terminator: u8,
+) -> Result<Vec<FileChunk>, ChunkError> {
     assert!(max_chunks > 0);
     assert!(!paths.is_empty());
-    let sizes: Vec<usize> = paths
-        .iter()
-        .map(|path| {
-            fs::metadata(path)
-                .expect("metadata")
-                .len()
-                .try_into()
-                .unwrap()
-        })
-        .collect();
-    let avg_size = (sizes.iter().copied().sum::<usize>() + paths.len() - 1) / paths.len();
-    paths
+    let sizes = paths
         .iter()
-        .zip(sizes.into_iter())
-        .flat_map(|(path, sz)| {
-            let desired_chunks: usize = (sz + avg_size - 1) / avg_size;
-            chunkify(&path, desired_chunks, min_size).into_iter()
-        })
-        .collect()
+        .map(|path| Ok(fs::metadata(path).map_err(ChunkError::FileNotFound)?.len()))
+        .collect::<Result<Vec<u64>, ChunkError>>()?;
+    let total_size = sizes.iter().sum::<u64>().max(1);
+    // Like the chunk boundaries in `chunkify`, file i gets the chunks
+    // between the proportional budget boundaries before and after it.
+    let mut cumulative_size = 0;
+    let mut allocated = 0;
+    let mut chunks = Vec::new();
+    for (path, sz) in paths.iter().zip(sizes) {
+        cumulative_size += sz;
+        // at most `max_chunks`, so it fits
+        let boundary = (max_chunks as u128 * cumulative_size as u128 / total_size as u128) as usize;
+        let desired_chunks = boundary - allocated;
+        allocated = boundary;
+        chunks.extend(chunkify_records(
+            path,
+            desired_chunks.max(1),
+            min_size,
+            compression,
+            terminator,
+        )?);
+    }
+    Ok(chunks)
 }
 
 /// Returns a list of up to `max_chunks` file chunks splitting up the given
@@ -95,33 +707,181 @@ pub fn chunkify_multiple(paths: &[PathBuf], max_chunks: usize, min_size: usize)
 /// Of course, the file is assumed to not be modified between the start
 /// of this method and the usage of the corresponding file chunks,
 /// else someone will panic.
-pub fn chunkify(path: &Path, max_chunks: usize, min_size: usize) -> Vec<FileChunk> {
+///
+/// An empty file yields no chunks at all. This fails if the file can't
+/// be read, or is too large to address with a `usize`, which only happens
+/// on targets narrower than 64 bits.
+///
+/// The file is decompressed according to `compression`, which is
+/// [detected](Compression::detect) if `None`. Compressed files are split
+/// by their decompressed size if possible.
+///
+/// Named pipes and character devices, as from process substitution like
+/// `<(curl ...)`, can't be sized or seeked, so they're read as a single
+/// [streamed](FileChunk::is_stream) chunk until EOF, which is uncompressed
+/// unless `compression` says otherwise.
+pub fn chunkify(
+    path: &Path,
+    max_chunks: usize,
+    min_size: usize,
+    compression: Option<Compression>,
+) -> Result<Vec<FileChunk>, ChunkError> {
+    chunkify_records(path, max_chunks, min_size, compression, b'\n')
+}
+
+/// Like [`chunkify`], but for files of records terminated by null bytes,
+/// as output by `find -print0`, so chunks are aligned on `\0` rather than
+/// newlines. The compression is always [detected](Compression::detect).
+pub fn chunkify_nullsep(
+    path: &Path,
+    max_chunks: usize,
+    min_size: usize,
+) -> Result<Vec<FileChunk>, ChunkError> {
+    chunkify_records(path, max_chunks, min_size, None, b'\0')
+}
+
+fn chunkify_records(
+    path: &Path,
+    max_chunks: usize,
+    min_size: usize,
+    compression: Option<Compression>,
+    terminator: u8,
+) -> Result<Vec<FileChunk>, ChunkError> {
     assert!(max_chunks > 0);
-    let metadata = fs::metadata(path).unwrap();
-    let size: usize = metadata.len().try_into().unwrap();
+    let metadata = fs::metadata(path).map_err(ChunkError::FileNotFound)?;
+    if is_stream(&metadata) {
+        // detecting compression would eat the magic bytes
+        let compression = compression.unwrap_or(Compression::None);
+        return Ok(vec![whole_file(path, compression, terminator)]);
+    }
+    let size = to_usize(metadata.len())?;
+    if size == 0 {
+        return Ok(Vec::new());
+    }
+    match compression.unwrap_or_else(|| Compression::detect(path)) {
+        Compression::None => (),
+        Compression::Gzip => return Ok(vec![whole_file(path, Compression::Gzip, terminator)]),
+        Compression::Zstd => {
+            return match zstd_frames(path) {
+                Some(frames) => {
+                    chunkify_zstd_frames(path, &frames, max_chunks, min_size, terminator)
+                }
+                None => Ok(vec![whole_file(path, Compression::Zstd, terminator)]),
+            };
+        }
+    }
+    let file = File::open(path).map_err(ChunkError::FileNotFound)?;
+    let ranges = chunk_ranges(file, size, max_chunks, min_size, terminator);
+    let chunks = ranges
+        .into_iter()
+        .map(|(start, stop)| FileChunk {
+            path: path.to_owned(),
+            start,
+            stop,
+            compression: Compression::None,
+            decode_from: (start as u64, start),
+            terminator,
+            checksum: None,
+        })
+        .collect();
+    Ok(chunks)
+}
+
+/// Converts a file size to a `usize`, which only fails on targets
+/// narrower than 64 bits; going back `as u64` is always lossless.
+fn to_usize(size: u64) -> Result<usize, ChunkError> {
+    size.try_into().map_err(|_| ChunkError::FileTooLarge(size))
+}
+
+/// The offset `i / n` of the way through `size` bytes, computed without
+/// overflowing even when `size` is near `usize::MAX`.
+fn split_point(size: usize, i: usize, n: usize) -> usize {
+    (size as u128 * i as u128 / n as u128) as usize
+}
+
+/// The byte offsets `[start, stop)` of a chunk, see [`chunkify_reader`].
+pub type ChunkRange = (usize, usize);
+
+/// Like [`chunkify`], but returns just the byte ranges of the chunks of
+/// the first `size` bytes of uncompressed `reader`, for input not known
+/// by a path, like an in-memory buffer or an already-open file
+/// descriptor.
+pub fn chunkify_reader<R: Read + Seek>(
+    reader: R,
+    size: usize,
+    max_chunks: usize,
+    min_size: usize,
+) -> Vec<ChunkRange> {
+    assert!(max_chunks > 0);
+    if size == 0 {
+        return Vec::new();
+    }
+    chunk_ranges(reader, size, max_chunks, min_size, b'\n')
+}
+
+/// Like [`chunkify_reader`], but for lines already in memory, so finding
+/// the ranges needs no I/O; every non-empty line can be its own chunk.
+pub fn chunkify_bytes(data: &[u8], max_chunks: usize) -> Vec<ChunkRange> {
+    assert!(max_chunks > 0);
+    if data.is_empty() {
+        return Vec::new();
+    }
+    byte_ranges(data, max_chunks, 1, b'\n')
+}
+
+/// The same ranges as [`chunk_ranges`], found in `data` directly.
+fn byte_ranges(data: &[u8], max_chunks: usize, min_size: usize, terminator: u8) -> Vec<ChunkRange> {
+    let size = data.len();
     let max_chunks = max_chunks.min(size / min_size).max(1);
+    let mut ranges = Vec::with_capacity(max_chunks);
+    let mut current_byte = 0;
+    for i in 0..max_chunks {
+        let stop = split_point(size, i + 1, max_chunks);
 
-    let mut file = File::open(path).unwrap();
-    let mut chunks = Vec::with_capacity(max_chunks);
+        // in the rare case when a line takes up a whole block, skip it
+        if current_byte >= stop {
+            continue;
+        }
+
+        let stop = match memchr::memchr(terminator, &data[stop..]) {
+            Some(i) => stop + i + 1,
+            None => size,
+        };
+
+        ranges.push((current_byte, stop));
+        current_byte = stop;
+
+        if stop == size {
+            break;
+        }
+    }
+
+    ranges
+}
+
+fn chunk_ranges<R: Read + Seek>(
+    mut reader: R,
+    size: usize,
+    max_chunks: usize,
+    min_size: usize,
+    terminator: u8,
+) -> Vec<ChunkRange> {
+    let max_chunks = max_chunks.min(size / min_size).max(1);
+    let mut ranges = Vec::with_capacity(max_chunks);
     let mut current_byte = 0;
     for i in 0..max_chunks {
-        let stop = size * (i + 1) / max_chunks;
+        let stop = split_point(size, i + 1, max_chunks);
 
         // in the rare case when a line takes up a whole block, skip it
         if current_byte >= stop {
             continue;
         }
 
-        file.seek(SeekFrom::Start(stop.try_into().unwrap()))
-            .expect("seek");
-        let mut reader = BufReader::new(&mut file);
-        let stop = stop + read_until(b'\n', &mut reader);
+        reader.seek(SeekFrom::Start(stop as u64)).expect("seek");
+        let mut buffered = BufReader::new(&mut reader);
+        let stop = (stop + read_until(terminator, &mut buffered)).min(size);
 
-        chunks.push(FileChunk {
-            path: path.to_owned(),
-            start: current_byte,
-            stop,
-        });
+        ranges.push((current_byte, stop));
         current_byte = stop;
 
         if stop == size {
@@ -129,7 +889,109 @@ pub fn chunkify(path: &Path, max_chunks: usize, min_size: usize) -> Vec<FileChun
         }
     }
 
-    chunks
+    ranges
+}
+
+/// Whether a file is a pipe or device, which has no length to split on.
+fn is_stream(metadata: &fs::Metadata) -> bool {
+    metadata.file_type().is_fifo() || metadata.file_type().is_char_device()
+}
+
+/// A single chunk reading a file, compressed or a stream, until EOF.
+fn whole_file(path: &Path, compression: Compression, terminator: u8) -> FileChunk {
+    FileChunk {
+        path: path.to_owned(),
+        start: 0,
+        stop: usize::MAX,
+        compression,
+        decode_from: (0, 0),
+        terminator,
+        checksum: None,
+    }
+}
+
+/// Splits a seekable zstd file into chunks on frame boundaries, given
+/// the `(compressed, decompressed)` size of each frame. Since frames
+/// needn't end on newlines, [`FileChunk::reader`] does the line alignment.
+fn chunkify_zstd_frames(
+    path: &Path,
+    frames: &[(u64, usize)],
+    max_chunks: usize,
+    min_size: usize,
+    terminator: u8,
+) -> Result<Vec<FileChunk>, ChunkError> {
+    let size = to_usize(frames.iter().map(|&(_, d)| d as u64).sum())?;
+    let max_chunks = max_chunks.min(size / min_size).max(1);
+    let mut chunks = Vec::with_capacity(max_chunks);
+    // compressed and decompressed offsets of the end of the current frame
+    let mut offsets = (0, 0);
+    let mut start = 0;
+    let mut decode_from = (0, 0);
+    for &(compressed, decompressed) in frames {
+        let frame_start = offsets;
+        offsets = (offsets.0 + compressed, offsets.1 + decompressed);
+        let target = split_point(size, chunks.len() + 1, max_chunks);
+        if offsets.1 >= target || offsets.1 == size {
+            chunks.push(FileChunk {
+                path: path.to_owned(),
+                start,
+                stop: offsets.1,
+                compression: Compression::Zstd,
+                decode_from,
+                terminator,
+                checksum: None,
+            });
+            start = offsets.1;
+            decode_from = frame_start;
+        }
+    }
+    Ok(chunks)
+}
+
+/// Parses the seek table of a file in the zstd seekable format, returning
+/// the `(compressed, decompressed)` size of each frame, or `None` if the
+/// file has no seek table.
+fn zstd_frames(path: &Path) -> Option<Vec<(u64, usize)>> {
+    let mut file = File::open(path).expect("file available");
+    let size = file.metadata().expect("metadata").len();
+    if size < ZSTD_SEEKABLE_FOOTER_SIZE {
+        return None;
+    }
+    let mut footer = [0u8; ZSTD_SEEKABLE_FOOTER_SIZE as usize];
+    file.seek(SeekFrom::End(-(ZSTD_SEEKABLE_FOOTER_SIZE as i64)))
+        .expect("seek");
+    file.read_exact(&mut footer).expect("read");
+    if le_u32(&footer[5..9]) != ZSTD_SEEKABLE_MAGIC {
+        return None;
+    }
+    let nframes = u64::from(le_u32(&footer[0..4]));
+    let entry_size = if footer[4] & 0x80 != 0 { 12 } else { 8 };
+    let table_size = nframes * entry_size + ZSTD_SEEKABLE_FOOTER_SIZE;
+    // the table is wrapped in an 8-byte skippable frame header
+    if size < table_size + 8 {
+        return None;
+    }
+    let mut table = vec![0u8; (table_size + 8) as usize];
+    file.seek(SeekFrom::Start(size - table_size - 8))
+        .expect("seek");
+    file.read_exact(&mut table).expect("read");
+    if le_u32(&table[0..4]) != ZSTD_SKIPPABLE_MAGIC {
+        return None;
+    }
+    let frames = table[8..]
+        .chunks_exact(entry_size as usize)
+        .take(nframes as usize)
+        .map(|entry| {
+            let compressed = u64::from(le_u32(&entry[0..4]));
+            let decompressed = le_u32(&entry[4..8]) as usize;
+            (compressed, decompressed)
+        })
+        .collect();
+    Some(frames)
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().unwrap())
 }
 
 fn read_until<R: BufRead + ?Sized>(delim: u8, r: &mut R) -> usize {
@@ -1,2 +1,4 @@
+pub mod cardinality;
 pub mod fileblocks;
+pub mod pipeline;
 pub mod sharder;
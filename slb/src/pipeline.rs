@@ -0,0 +1,421 @@
+//! Programmatic driver for the full flatmap-fold pipeline.
+//!
+//! This runs the same map, shard, and fold stages as the `slb` executable,
+//! but the mapper and folder may be either child processes or in-process
+//! closures, so `slb` can be embedded in other programs.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use bstr::io::BufReadExt;
+use bytes::Bytes;
+
+use crate::fileblocks::{self, ChunkError, Compression, FileChunk};
+use crate::sharder::{self, Key, ShardMode, ShardOptions};
+
+const QUEUE_SIZE: usize = 256;
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// A pure function from input bytes to output bytes.
+pub type ByteFn = Box<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+/// A user-specified mapper or folder stage.
+pub enum Stage {
+    /// A child process. A fresh instance of the same command is spawned
+    /// for every input chunk (as a mapper) or partition (as a folder),
+    /// and it's fed its input over stdin.
+    Command(Command),
+    /// An in-process function. As a mapper, it's called with every
+    /// newline-terminated input line and should return the flat-mapped
+    /// output lines. As a folder, it's called once with all the lines of
+    /// its partition and should return the partition's output.
+    Closure(ByteFn),
+}
+
+impl Stage {
+    /// Convenience constructor for a [`Stage::Closure`].
+    pub fn closure<F>(f: F) -> Self
+    where
+        F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        Stage::Closure(Box::new(f))
+    }
+}
+
+impl From<Command> for Stage {
+    fn from(cmd: Command) -> Self {
+        Stage::Command(cmd)
+    }
+}
+
+type SharedFn = Arc<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+/// Like [`Stage`] but with closures shareable across threads.
+enum SharedStage {
+    Command(Command),
+    Closure(SharedFn),
+}
+
+impl From<Stage> for SharedStage {
+    fn from(stage: Stage) -> Self {
+        match stage {
+            Stage::Command(cmd) => SharedStage::Command(cmd),
+            Stage::Closure(f) => SharedStage::Closure(Arc::from(f)),
+        }
+    }
+}
+
+/// Builder for a flatmap-fold run over a set of input files.
+///
+/// ```no_run
+/// use std::process::Command;
+/// use slb::pipeline::{Pipeline, Stage};
+///
+/// let mut folder = Command::new("sort");
+/// folder.arg("-u");
+/// Pipeline::new(Stage::Command(folder))
+///     .mapper(Stage::closure(|line| line.to_ascii_lowercase()))
+///     .infile("input.txt")
+///     .outprefix("out.")
+///     .run()
+///     .expect("pipeline");
+/// ```
+pub struct Pipeline {
+    mapper: Option<Stage>,
+    folder: Stage,
+    infiles: Vec<PathBuf>,
+    outprefix: Option<PathBuf>,
+    nthreads: usize,
+    bufsize: usize,
+    bufsize_per_partition: bool,
+    key: Key,
+    mode: ShardMode,
+    compression: Option<Compression>,
+    flush_interval: Option<Duration>,
+}
+
+impl Pipeline {
+    /// Creates a pipeline with the given folder and an identity mapper.
+    pub fn new(folder: Stage) -> Self {
+        Self {
+            mapper: None,
+            folder,
+            infiles: Vec::new(),
+            outprefix: None,
+            nthreads: num_cpus::get_physical(),
+            bufsize: 64 * 1024,
+            bufsize_per_partition: false,
+            key: Key::default(),
+            mode: ShardMode::default(),
+            compression: None,
+            flush_interval: None,
+        }
+    }
+
+    /// Sets the flat-map stage, which defaults to the identity.
+    pub fn mapper(mut self, mapper: Stage) -> Self {
+        self.mapper = Some(mapper);
+        self
+    }
+
+    /// Adds an input file.
+    pub fn infile<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.infiles.push(path.into());
+        self
+    }
+
+    /// Adds several input files.
+    pub fn infiles<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.infiles.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets the prefix for the per-partition output files, see
+    /// [`output_path`].
+    pub fn outprefix<P: Into<PathBuf>>(mut self, outprefix: P) -> Self {
+        self.outprefix = Some(outprefix.into());
+        self
+    }
+
+    /// Sets the approximate target number of parallel mappers, which
+    /// also bounds the number of folders. Defaults to num CPUs.
+    pub fn nthreads(mut self, nthreads: usize) -> Self {
+        self.nthreads = nthreads;
+        self
+    }
+
+    /// Sets the buffer size in bytes for mapper output before it's sent
+    /// to folders. Defaults to 64 KB.
+    pub fn bufsize(mut self, bufsize: usize) -> Self {
+        self.bufsize = bufsize;
+        self
+    }
+
+    /// Makes `bufsize` apply to each folder's buffer separately, rather
+    /// than to the total across folders. This multiplies memory use by
+    /// the number of folders.
+    pub fn bufsize_per_partition(mut self) -> Self {
+        self.bufsize_per_partition = true;
+        self
+    }
+
+    /// Sets which part of each mapped line is its key. Defaults to the
+    /// first space-delimited word.
+    pub fn key(mut self, key: Key) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Sets how mapped lines are routed to folders. Defaults to hashing
+    /// keys with [`sharder::HashFn::SipHash`].
+    pub fn shard_mode(mut self, mode: ShardMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets the compression of all input files, which is otherwise
+    /// detected per file.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Sets a maximum time mapper output is buffered before it's sent to
+    /// folders, regardless of `bufsize`. By default, there's none.
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = Some(interval);
+        self
+    }
+
+    /// Runs the pipeline to completion, returning the first error
+    /// encountered, including failing exit statuses from child processes.
+    pub fn run(self) -> io::Result<()> {
+        let outprefix = self
+            .outprefix
+            .ok_or_else(|| invalid_input("no outprefix given"))?;
+        if self.infiles.is_empty() {
+            return Err(invalid_input("no input files given"));
+        }
+        if self.nthreads == 0 || self.bufsize == 0 {
+            return Err(invalid_input("nthreads and bufsize must be positive"));
+        }
+        let chunks = fileblocks::chunkify_multiple(
+            &self.infiles,
+            self.nthreads,
+            READ_CHUNK_SIZE,
+            self.compression,
+        )
+        .map_err(|e| match e {
+            ChunkError::FileNotFound(e) => e,
+            e => io::Error::new(ErrorKind::InvalidInput, e),
+        })?;
+        // empty input still gets a folder
+        let npartitions = chunks.len().max(1);
+        let (txs, rxs): (Vec<_>, Vec<_>) =
+            (0..npartitions).map(|_| sync_channel(QUEUE_SIZE)).unzip();
+
+        let mut folder = SharedStage::from(self.folder);
+        let folder_threads = rxs
+            .into_iter()
+            .enumerate()
+            .map(|(i, rx)| {
+                let file = File::create(output_path(&outprefix, i, npartitions))?;
+                spawn_folder(&mut folder, i, rx, file)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut mapper = self.mapper.map(SharedStage::from);
+        let txs = Arc::new(txs);
+        let sharding = Sharding {
+            npartitions,
+            bufsize: self.bufsize,
+            bufsize_per_partition: self.bufsize_per_partition,
+            key: self.key,
+            mode: self.mode,
+            flush_interval: self.flush_interval,
+        };
+        let mapper_threads = chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let txs = Arc::clone(&txs);
+                let send = move |ix: usize, buf: Bytes| {
+                    // A hung-up folder reports its own error on join.
+                    let _ = txs[ix].send(buf);
+                };
+                spawn_mapper(mapper.as_mut(), i, chunk, sharding.clone(), send)
+            })
+            .collect::<io::Result<Vec<_>>>();
+        drop(txs);
+
+        // Join everything before reporting so no thread is left running.
+        let mut result = Ok(());
+        for handle in mapper_threads?.into_iter().chain(folder_threads) {
+            let joined = join(handle);
+            if result.is_ok() {
+                result = joined;
+            }
+        }
+        result
+    }
+}
+
+/// Returns the output path for partition `index` out of `npartitions`,
+/// which appends the zero-padded index to the file name of `outprefix`.
+pub fn output_path(outprefix: &Path, index: usize, npartitions: usize) -> PathBuf {
+    let width = format!("{}", npartitions.saturating_sub(1)).len();
+    let suffix = format!("{:0>width$}", index, width = width);
+    let mut fname = outprefix.file_name().expect("file name").to_owned();
+    fname.push(&suffix);
+    outprefix.with_file_name(fname)
+}
+
+/// Parameters for [`sharder::shard`] shared by all mappers.
+#[derive(Clone)]
+struct Sharding {
+    npartitions: usize,
+    bufsize: usize,
+    bufsize_per_partition: bool,
+    key: Key,
+    mode: ShardMode,
+    flush_interval: Option<Duration>,
+}
+
+impl Sharding {
+    fn shard<R, F>(&self, r: R, f: F)
+    where
+        R: BufRead,
+        F: FnMut(usize, Bytes),
+    {
+        sharder::shard(
+            r,
+            self.npartitions,
+            self.bufsize,
+            &self.key,
+            self.mode,
+            ShardOptions {
+                bufsize_per_partition: self.bufsize_per_partition,
+                flush_interval: self.flush_interval,
+                ..ShardOptions::default()
+            },
+            f,
+        );
+    }
+}
+
+fn spawn_mapper<F>(
+    mapper: Option<&mut SharedStage>,
+    index: usize,
+    chunk: FileChunk,
+    sharding: Sharding,
+    send: F,
+) -> io::Result<JoinHandle<io::Result<()>>>
+where
+    F: FnMut(usize, Bytes) + Send + 'static,
+{
+    let handle = match mapper {
+        None => thread::spawn(move || {
+            let input = chunk.reader();
+            sharding.shard(input, send);
+            Ok(())
+        }),
+        Some(SharedStage::Command(cmd)) => {
+            let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+            let mut stdin = child.stdin.take().expect("child stdin");
+            let stdout = child.stdout.take().expect("child stdout");
+            thread::spawn(move || {
+                let feeder = thread::spawn(move || {
+                    match io::copy(&mut chunk.reader(), &mut stdin) {
+                        // The mapper may legitimately stop reading early.
+                        Err(e) if e.kind() != ErrorKind::BrokenPipe => Err(e),
+                        _ => Ok(()),
+                    }
+                });
+                sharding.shard(BufReader::new(stdout), send);
+                join(feeder)?;
+                check_status(child.wait()?, "mapper", index)
+            })
+        }
+        Some(SharedStage::Closure(f)) => {
+            let f = Arc::clone(f);
+            thread::spawn(move || {
+                let mut send = send;
+                let mut mapped = Vec::with_capacity(sharding.bufsize);
+                chunk.reader().for_byte_line_with_terminator(|line| {
+                    mapped.extend_from_slice(&f(line));
+                    if mapped.len() >= sharding.bufsize {
+                        sharding.shard(&mapped[..], &mut send);
+                        mapped.clear();
+                    }
+                    Ok(true)
+                })?;
+                sharding.shard(&mapped[..], send);
+                Ok(())
+            })
+        }
+    };
+    Ok(handle)
+}
+
+fn spawn_folder(
+    folder: &mut SharedStage,
+    index: usize,
+    rx: Receiver<Bytes>,
+    mut file: File,
+) -> io::Result<JoinHandle<io::Result<()>>> {
+    let handle = match folder {
+        SharedStage::Command(cmd) => {
+            let mut child = cmd.stdin(Stdio::piped()).stdout(file).spawn()?;
+            let mut stdin = child.stdin.take().expect("child stdin");
+            thread::spawn(move || {
+                while let Ok(lines) = rx.recv() {
+                    stdin.write_all(&lines)?;
+                }
+                drop(stdin);
+                check_status(child.wait()?, "folder", index)
+            })
+        }
+        SharedStage::Closure(f) => {
+            let f = Arc::clone(f);
+            thread::spawn(move || {
+                let mut input = Vec::new();
+                while let Ok(lines) = rx.recv() {
+                    input.extend_from_slice(&lines);
+                }
+                file.write_all(&f(&input))
+            })
+        }
+    };
+    Ok(handle)
+}
+
+fn check_status(status: ExitStatus, stage: &str, index: usize) -> io::Result<()> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "{} {} failed: {}",
+            stage, index, status
+        )))
+    }
+}
+
+fn join(handle: JoinHandle<io::Result<()>>) -> io::Result<()> {
+    handle
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("pipeline thread panicked")))
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, msg)
+}
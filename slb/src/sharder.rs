@@ -1,70 +1,1396 @@
 //! Shard by first key into buffers.
 
-use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryInto;
-use std::hash::{Hash, Hasher};
-use std::io::BufRead;
+use std::fmt;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, BufRead, BufWriter, Write};
 use std::mem;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use bstr::io::BufReadExt;
+use bstr::ByteSlice;
+use bytes::{Bytes, BytesMut};
+use fnv::FnvHasher;
 use memchr::memchr;
+use regex::bytes::Regex;
+use serde_json::Value;
+use siphasher::sip::SipHasher13;
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+use crate::cardinality::HyperLogLog;
+
+/// The hash function used to assign keys to partitions.
+///
+/// Unlike `std`'s `DefaultHasher`, each of these is fixed across
+/// platforms, Rust versions, and runs, so the same key is always routed
+/// to the same partition for a given partition count.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum HashFn {
+    /// 64-bit FNV-1a, fast for short keys.
+    Fnv,
+    /// 64-bit xxHash, fast for long keys.
+    XxHash,
+    /// SipHash-1-3 with zero keys, the algorithm behind `DefaultHasher`.
+    #[default]
+    SipHash,
+}
+
+impl HashFn {
+    /// Hashes the given key bytes.
+    pub fn hash(self, key: &[u8]) -> u64 {
+        // Hash the raw bytes rather than using the `Hash` impl for slices,
+        // which mixes in a platform-dependent length prefix.
+        match self {
+            HashFn::Fnv => {
+                let mut hasher = FnvHasher::default();
+                hasher.write(key);
+                hasher.finish()
+            }
+            HashFn::XxHash => xxhash_rust::xxh64::xxh64(key, 0),
+            HashFn::SipHash => {
+                let mut hasher = SipHasher13::new_with_keys(0, 0);
+                hasher.write(key);
+                hasher.finish()
+            }
+        }
+    }
+}
+
+impl FromStr for HashFn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fnv" => Ok(HashFn::Fnv),
+            "xxhash" => Ok(HashFn::XxHash),
+            "siphash" => Ok(HashFn::SipHash),
+            _ => Err(format!("unknown hasher {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for HashFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HashFn::Fnv => "fnv",
+            HashFn::XxHash => "xxhash",
+            HashFn::SipHash => "siphash",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Explicit partitions for some keys, which take precedence over the
+/// [`ShardMode`], e.g., to isolate hot keys in partitions of their own.
+/// Other keys are routed as usual.
+pub type PartitionMap = HashMap<Vec<u8>, usize>;
+
+/// How lines are routed to partitions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShardMode {
+    /// All lines with the same key go to the same partition, determined
+    /// by the key's hash.
+    Hash(HashFn),
+    /// All lines with the same key go to the same partition, determined
+    /// by a [`ConsistentHasher`], so that changing the partition count
+    /// moves as few keys as possible.
+    ConsistentHash(ConsistentHasher),
+    /// Line `i` goes to partition `i % npartitions`, ignoring keys
+    /// entirely, which evens out load when key locality isn't needed.
+    RoundRobin,
+}
+
+impl Default for ShardMode {
+    fn default() -> Self {
+        ShardMode::Hash(HashFn::default())
+    }
+}
+
+/// Jump consistent hashing (Lamping and Veach, 2014) of keys to
+/// partitions.
+///
+/// Each key's hash stands for a virtual bucket, which is jumped to a
+/// partition such that growing the partition count from `n` to `m`
+/// only moves keys into the new partitions `n..m`, and each either stays
+/// put or moves with probability `(m - n) / m`. So output from a run
+/// with 8 folders can be combined with that of a later run with 16: every
+/// key of old partition `i` is in either new partition `i` or one of
+/// `8..16`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct ConsistentHasher {
+    hasher: HashFn,
+}
+
+impl ConsistentHasher {
+    /// Creates a consistent hasher on top of the given key hash.
+    pub fn new(hasher: HashFn) -> Self {
+        Self { hasher }
+    }
+
+    /// Returns the partition of `key` out of `npartitions`.
+    pub fn partition(&self, key: &[u8], npartitions: usize) -> usize {
+        let mut state = self.hasher.hash(key);
+        let mut bucket = 0;
+        let mut next: u64 = 0;
+        while next < npartitions as u64 {
+            bucket = next;
+            state = state
+                .wrapping_mul(2_862_933_555_777_941_757)
+                .wrapping_add(1);
+            next =
+                ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((state >> 33) + 1) as f64)) as u64;
+        }
+        bucket as usize
+    }
+}
+
+/// Which part of each line is its key.
+#[derive(Clone, Debug)]
+pub enum Key {
+    /// All bytes leading up to the first occurrence of the delimiter,
+    /// e.g., `b' '` for space-separated words or `b'\t'` for TSV. Lines
+    /// without the delimiter are keyed by the whole line.
+    Delimited(u8),
+    /// The value at the given JSON Pointer (RFC 6901) in each line, which
+    /// is parsed as a JSON document. String values are keyed by their
+    /// contents, and other values by their compact JSON encoding.
+    JsonPointer(String),
+    /// The given 1-indexed fields of each line split on `delimiter`,
+    /// joined by `delimiter` in the listed order, like `sort -k`. Fields
+    /// past the end of a line are skipped.
+    Fields { delimiter: u8, fields: Vec<usize> },
+    /// The first capture group of the regex's leftmost match in each
+    /// line, or the whole match if the regex has no groups. Lines without
+    /// a match are keyed by the whole line.
+    Regex(Regex),
+    /// The bytes at offsets `start..end` of each line (excluding its
+    /// terminator), for fixed-width records. The range is truncated to the
+    /// line, so lines shorter than `start` have an empty key.
+    ByteRange { start: usize, end: usize },
+    /// The key of the inner [`Key`], normalized by a [`KeyTransform`], so
+    /// that, e.g., keys differing only in case go to the same partition.
+    Transformed(Box<Key>, KeyTransform),
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Key::Delimited(a), Key::Delimited(b)) => a == b,
+            (Key::JsonPointer(a), Key::JsonPointer(b)) => a == b,
+            (
+                Key::Fields { delimiter, fields },
+                Key::Fields {
+                    delimiter: other_delimiter,
+                    fields: other_fields,
+                },
+            ) => delimiter == other_delimiter && fields == other_fields,
+            (Key::Regex(a), Key::Regex(b)) => a.as_str() == b.as_str(),
+            (
+                Key::ByteRange { start, end },
+                Key::ByteRange {
+                    start: other_start,
+                    end: other_end,
+                },
+            ) => start == other_start && end == other_end,
+            (Key::Transformed(a, a_transform), Key::Transformed(b, b_transform)) => {
+                a == b && a_transform == b_transform
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Key {}
+
+impl Default for Key {
+    fn default() -> Self {
+        Key::Delimited(b' ')
+    }
+}
+
+impl Key {
+    /// Extracts the key from a line ending in `terminator`, or returns
+    /// `None` if it has none. The terminator is never part of the key, so
+    /// an unterminated last line has the same key as it would otherwise.
+    pub fn extract<'a>(&self, line: &'a [u8], terminator: u8) -> Option<KeyBytes<'a>> {
+        let line = line.strip_suffix(&[terminator]).unwrap_or(line);
+        match self {
+            Key::Delimited(delimiter) => {
+                let end = memchr(*delimiter, line).unwrap_or(line.len());
+                Some(KeyBytes::Borrowed(&line[..end]))
+            }
+            Key::JsonPointer(pointer) => {
+                let value: Value = serde_json::from_slice(line).ok()?;
+                match value.pointer(pointer)? {
+                    Value::String(s) => Some(KeyBytes::Owned(s.as_bytes().to_vec())),
+                    other => Some(KeyBytes::Owned(other.to_string().into_bytes())),
+                }
+            }
+            Key::Fields { delimiter, fields } => {
+                let columns: Vec<_> = line.split(|&b| b == *delimiter).collect();
+                let present = fields
+                    .iter()
+                    .filter_map(|&field| columns.get(field.checked_sub(1)?));
+                let mut key = Vec::new();
+                for (i, column) in present.enumerate() {
+                    if i > 0 {
+                        key.push(*delimiter);
+                    }
+                    key.extend_from_slice(column);
+                }
+                Some(KeyBytes::Owned(key))
+            }
+            Key::Regex(regex) => {
+                let key = match regex.captures(line) {
+                    Some(captures) => captures.get(1).or_else(|| captures.get(0)),
+                    None => None,
+                };
+                Some(KeyBytes::Borrowed(key.map_or(line, |m| m.as_bytes())))
+            }
+            Key::ByteRange { start, end } => {
+                let end = (*end).min(line.len());
+                Some(KeyBytes::Borrowed(&line[(*start).min(end)..end]))
+            }
+            Key::Transformed(key, transform) => {
+                let key = key.extract(line, terminator)?;
+                Some(transform.apply(key))
+            }
+        }
+    }
+}
+
+/// The bytes of a key extracted by [`Key::extract`]. Keys that are
+/// rewritten rather than sliced out of their line, e.g., by a
+/// [`KeyTransform`], are kept on the stack if they fit in
+/// [`KeyBytes::INLINE_LEN`] bytes, so that short keys, the common case,
+/// don't allocate.
+#[derive(Clone, Debug)]
+pub enum KeyBytes<'a> {
+    /// Bytes of the line itself.
+    Borrowed(&'a [u8]),
+    /// The first `len` bytes of the buffer.
+    Inline {
+        buf: [u8; KeyBytes::INLINE_LEN],
+        len: usize,
+    },
+    /// Bytes on the heap.
+    Owned(Vec<u8>),
+}
+
+impl KeyBytes<'_> {
+    /// The longest key kept on the stack.
+    pub const INLINE_LEN: usize = 128;
+
+    /// Copies `bytes`, onto the stack if they fit.
+    pub fn copied(bytes: &[u8]) -> KeyBytes<'static> {
+        if bytes.len() <= Self::INLINE_LEN {
+            let mut buf = [0; Self::INLINE_LEN];
+            buf[..bytes.len()].copy_from_slice(bytes);
+            KeyBytes::Inline {
+                buf,
+                len: bytes.len(),
+            }
+        } else {
+            KeyBytes::Owned(bytes.to_vec())
+        }
+    }
+
+    /// Returns the bytes on the heap, copying them if they aren't already.
+    pub fn into_owned(self) -> Vec<u8> {
+        match self {
+            KeyBytes::Owned(bytes) => bytes,
+            bytes => bytes.to_vec(),
+        }
+    }
+
+    /// Returns the bytes for modification, copying borrowed ones first.
+    fn to_mut(&mut self) -> &mut [u8] {
+        if let KeyBytes::Borrowed(bytes) = *self {
+            *self = KeyBytes::copied(bytes);
+        }
+        match self {
+            KeyBytes::Borrowed(_) => unreachable!("copied above"),
+            KeyBytes::Inline { buf, len } => &mut buf[..*len],
+            KeyBytes::Owned(bytes) => bytes,
+        }
+    }
+}
+
+impl Default for KeyBytes<'_> {
+    fn default() -> Self {
+        KeyBytes::Borrowed(&[])
+    }
+}
+
+impl Deref for KeyBytes<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            KeyBytes::Borrowed(bytes) => bytes,
+            KeyBytes::Inline { buf, len } => &buf[..*len],
+            KeyBytes::Owned(bytes) => bytes,
+        }
+    }
+}
+
+/// A normalization of the bytes of a key, see [`Key::Transformed`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyTransform {
+    /// Lowercase ASCII letters.
+    Lowercase,
+    /// Uppercase ASCII letters.
+    Uppercase,
+    /// Strip leading and trailing Unicode whitespace.
+    Trim,
+    /// Strip leading Unicode whitespace.
+    TrimStart,
+    /// Strip trailing Unicode whitespace.
+    TrimEnd,
+}
+
+impl KeyTransform {
+    // Keys that are already in the right case, the common case, are passed
+    // through as is, and only a borrowed key gets copied.
+    fn apply(self, mut key: KeyBytes<'_>) -> KeyBytes<'_> {
+        match self {
+            KeyTransform::Lowercase if key.iter().any(u8::is_ascii_uppercase) => {
+                key.to_mut().make_ascii_lowercase();
+                key
+            }
+            KeyTransform::Uppercase if key.iter().any(u8::is_ascii_lowercase) => {
+                key.to_mut().make_ascii_uppercase();
+                key
+            }
+            KeyTransform::Trim => trim(key, ByteSlice::trim),
+            KeyTransform::TrimStart => trim(key, ByteSlice::trim_start),
+            KeyTransform::TrimEnd => trim(key, ByteSlice::trim_end),
+            _ => key,
+        }
+    }
+}
+
+/// Applies a trimming function to a key, only copying a key not borrowed
+/// from its line that actually has whitespace to trim.
+fn trim<'a>(key: KeyBytes<'a>, trim: fn(&[u8]) -> &[u8]) -> KeyBytes<'a> {
+    match key {
+        KeyBytes::Borrowed(key) => KeyBytes::Borrowed(trim(key)),
+        key if trim(&key).len() == key.len() => key,
+        key => KeyBytes::copied(trim(&key)),
+    }
+}
+
+impl FromStr for KeyTransform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lowercase" => Ok(KeyTransform::Lowercase),
+            "uppercase" => Ok(KeyTransform::Uppercase),
+            "trim" => Ok(KeyTransform::Trim),
+            "trim-start" => Ok(KeyTransform::TrimStart),
+            "trim-end" => Ok(KeyTransform::TrimEnd),
+            _ => Err(format!("unknown key transform {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for KeyTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            KeyTransform::Lowercase => "lowercase",
+            KeyTransform::Uppercase => "uppercase",
+            KeyTransform::Trim => "trim",
+            KeyTransform::TrimStart => "trim-start",
+            KeyTransform::TrimEnd => "trim-end",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What [`shard`] does with lines over a [`LineLimit`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LongLines {
+    /// Cut the line down to the maximum length, keeping its terminator.
+    Truncate,
+    /// Stop reading, routing no more lines.
+    Error,
+    /// Drop the line entirely.
+    Skip,
+}
+
+impl FromStr for LongLines {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate" => Ok(LongLines::Truncate),
+            "error" => Ok(LongLines::Error),
+            "skip" => Ok(LongLines::Skip),
+            _ => Err(format!("unknown long line policy {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for LongLines {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LongLines::Truncate => "truncate",
+            LongLines::Error => "error",
+            LongLines::Skip => "skip",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A maximum line length for [`shard`], which bounds the memory a single
+/// pathological line can take up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineLimit {
+    /// The maximum length in bytes, excluding the line terminator.
+    pub max_len: usize,
+    /// What to do with longer lines.
+    pub policy: LongLines,
+}
+
+/// How [`shard`] joins the lines of a [`Window`] into one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum WindowFormat {
+    /// The lines without their terminators, separated by tabs.
+    #[default]
+    Tab,
+    /// A JSON array of the lines without their terminators, as strings,
+    /// with invalid UTF-8 replaced.
+    Json,
+}
+
+impl FromStr for WindowFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tab" => Ok(WindowFormat::Tab),
+            "json" => Ok(WindowFormat::Json),
+            _ => Err(format!("unknown window format {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for WindowFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            WindowFormat::Tab => "tab",
+            WindowFormat::Json => "json",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Windows of consecutive lines with the same key for [`shard`], each of
+/// which is routed as a single line, so a partition sees lines in the
+/// context of their neighbors.
+///
+/// A window ends once it has `size` lines, or with the next line with a
+/// different key, or at the end of input. In [`ShardMode::RoundRobin`],
+/// every window has `size` lines but the last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Window {
+    /// How many lines make a full window.
+    pub size: usize,
+    /// How the window's lines are joined.
+    pub format: WindowFormat,
+}
+
+/// Numbering of the lines routed by [`shard`], which are each prefixed
+/// with `<number>\t`, or `<source>:<number>\t` with a `source`, after
+/// their key is extracted.
+///
+/// Numbers start at 1 and are drawn from `counter`, which can be shared
+/// between concurrent calls to number all of their lines uniquely.
+#[derive(Clone, Debug, Default)]
+pub struct LineNumbers {
+    /// The last number handed out.
+    pub counter: Arc<AtomicU64>,
+    /// An index of the input, e.g., its chunk.
+    pub source: Option<usize>,
+}
+
+/// How many lines [`shard`] reads between clock checks for its
+/// [`ShardOptions::flush_interval`], which keeps the overhead of timekeeping negligible.
+pub const CLOCK_CHECK_LINES: usize = 1000;
+
+/// Load statistics for a single [`shard`] call.
+///
+/// With a [`Window`], each window counts as one line, except for
+/// [`ShardStats::keyless_lines`] and [`ShardStats::long_lines`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShardStats {
+    /// Total lines routed.
+    pub lines: usize,
+    /// Total bytes routed, including line terminators.
+    pub bytes: usize,
+    /// Lines routed to each partition.
+    pub partition_lines: Vec<usize>,
+    /// Bytes routed to each partition.
+    pub partition_bytes: Vec<usize>,
+    /// Number of buffers flushed, i.e., calls to the callback.
+    pub flushes: usize,
+    /// Lines without a key (see [`Key::JsonPointer`]), which were all
+    /// routed to partition 0.
+    pub keyless_lines: usize,
+    /// Lines over the [`LineLimit`], which were handled according to its
+    /// policy. With [`LongLines::Error`], this is at most 1, and nonzero
+    /// if reading stopped early.
+    pub long_lines: usize,
+    /// A sketch of the distinct keys seen, which aren't extracted in
+    /// [`ShardMode::RoundRobin`], so then it's empty.
+    pub distinct_keys: HyperLogLog,
+}
 
 /// Reads from `r` until EOF, calling `f` occasionally with
 /// the arguments `(index, buffer)` where `index` is the index
 /// of the partition that the hash key (first word of each line)
-/// falls into and `buffer` is a byte buffer of newline-terminated byte
+/// falls into and `buffer` is a shared byte buffer of newline-terminated byte
 /// lines (there could be multiple, but each line starts with a key in that
-/// hash space partition).
+/// hash space partition). A final line without a trailing newline has one
+/// added.
+///
+/// `bufsize` is the total size of the buffers across all partitions that
+/// triggers flushing all of them, unless
+/// [`ShardOptions::bufsize_per_partition`] is set.
+///
+/// `key` picks out the part of each line that's hashed, and `mode`
+/// determines which partition each line lands in; with
+/// [`ShardMode::RoundRobin`] the `key` is unused.
+///
+/// Lines without a key (see [`Key::JsonPointer`]) are sent to partition 0.
+/// Returns statistics about how lines were distributed.
 ///
-/// `bufsize` is the size of each buffer per partition before flush.
-pub fn shard<R, F>(r: R, npartitions: usize, bufsize: usize, mut f: F)
+/// Panics if any partition of [`ShardOptions::partition_map`] isn't less
+/// than `npartitions`.
+pub fn shard<R, F>(
+    r: R,
+    npartitions: usize,
+    bufsize: usize,
+    key: &Key,
+    mode: ShardMode,
+    options: ShardOptions,
+    f: F,
+) -> ShardStats
 where
     R: BufRead,
-    F: FnMut(usize, Vec<u8>),
+    F: FnMut(usize, Bytes),
 {
-    let mut used_space = 0;
-    let mut bufs = vec![Vec::new(); npartitions];
-    let npartitions: u64 = npartitions.try_into().unwrap();
+    shard_records(r, b'\n', npartitions, bufsize, key, mode, options, f)
+}
+
+/// The optional behaviors of [`shard`] and its variants, all off by
+/// default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShardOptions<'a> {
+    /// Makes `bufsize` the size that triggers flushing a single partition's
+    /// buffer, rather than all of them, which avoids many tiny flushes with
+    /// many partitions but lets memory use grow to `npartitions * bufsize`.
+    pub bufsize_per_partition: bool,
+    /// After every flush, the coefficient of variation (standard deviation
+    /// over mean) of per-partition line counts so far is checked against
+    /// this, and the first time it's exceeded a warning with the most
+    /// frequent keys is printed to stderr.
+    pub skew_threshold: Option<f64>,
+    /// All buffers are also flushed once this much time has passed since
+    /// the last flush, so that lines bound for rarely-hit partitions aren't
+    /// held back indefinitely. The clock is only checked every
+    /// [`CLOCK_CHECK_LINES`] lines, and only as lines arrive.
+    pub flush_interval: Option<Duration>,
+    /// Lines over this are truncated or skipped, or stop reading altogether
+    /// (see [`ShardStats::long_lines`]), before their key is extracted.
+    pub line_limit: Option<LineLimit>,
+    /// Consecutive lines with the same key are routed together as a single
+    /// line, see [`Window`]. Keyless lines are windowed together too.
+    pub window: Option<Window>,
+    /// Every line routed is numbered, see [`LineNumbers`].
+    pub line_numbers: Option<&'a LineNumbers>,
+    /// Lines with a key in this map go to the partition it maps the key
+    /// to, and only the rest are routed by `mode`.
+    pub partition_map: Option<&'a PartitionMap>,
+}
+
+/// Like [`shard`] with the default key and mode, but writes each
+/// partition's lines to the corresponding file of `output_paths`, which
+/// are created (or truncated) first, one per partition.
+///
+/// Fails if any file can't be created or written, in which case nothing
+/// more is written after the first failed write.
+pub fn shard_to_files<R: BufRead>(
+    r: R,
+    output_paths: &[PathBuf],
+    bufsize: usize,
+) -> io::Result<ShardStats> {
+    assert!(!output_paths.is_empty());
+    let mut files = output_paths
+        .iter()
+        .map(|path| File::create(path).map(BufWriter::new))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut write_result = Ok(());
+    let stats = shard(
+        r,
+        files.len(),
+        bufsize,
+        &Key::default(),
+        ShardMode::default(),
+        ShardOptions::default(),
+        |ix, buf: Bytes| {
+            if write_result.is_ok() {
+                write_result = files[ix].write_all(&buf);
+            }
+        },
+    );
+    write_result?;
+    for file in &mut files {
+        file.flush()?;
+    }
+    Ok(stats)
+}
+
+/// Writes lines to a file of their own for each distinct key, rather than
+/// to a fixed number of partitions, e.g., for a few dozen country codes.
+/// Each key's file is created (or truncated) at `path_of(key)` the first
+/// time the key is seen. Lines without a key go with those whose key is
+/// empty.
+///
+/// Lines may be written any number at a time, and split anywhere, but an
+/// unterminated last line is only written by [`KeyFiles::finish`].
+/// Writing fails on the first line whose key would take more than
+/// `max_files` files.
+pub struct KeyFiles<P> {
+    key: Key,
+    terminator: u8,
+    max_files: usize,
+    path_of: P,
+    files: HashMap<Vec<u8>, BufWriter<File>>,
+    // the start of a line split across writes
+    partial: Vec<u8>,
+}
+
+impl<P: FnMut(&[u8]) -> PathBuf> KeyFiles<P> {
+    /// Returns a writer for lines ending in `terminator`, with no files
+    /// opened yet.
+    pub fn new(key: Key, terminator: u8, max_files: usize, path_of: P) -> Self {
+        Self {
+            key,
+            terminator,
+            max_files,
+            path_of,
+            files: HashMap::new(),
+            partial: Vec::new(),
+        }
+    }
+
+    /// Writes any unterminated last line, with a terminator added, and
+    /// flushes every file, returning how many there are.
+    pub fn finish(mut self) -> io::Result<usize> {
+        if !self.partial.is_empty() {
+            let mut line = mem::take(&mut self.partial);
+            line.push(self.terminator);
+            self.write_line(&line)?;
+        }
+        self.flush()?;
+        Ok(self.files.len())
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let key = self.key.extract(line, self.terminator).unwrap_or_default();
+        if !self.files.contains_key(&*key) {
+            if self.files.len() == self.max_files {
+                return Err(io::Error::other(format!(
+                    "more than {} distinct keys",
+                    self.max_files
+                )));
+            }
+            let file = File::create((self.path_of)(&key))?;
+            self.files.insert(key.to_vec(), BufWriter::new(file));
+        }
+        self.files.get_mut(&*key).expect("key file").write_all(line)
+    }
+}
+
+impl<P: FnMut(&[u8]) -> PathBuf> Write for KeyFiles<P> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while let Some(end) = memchr(self.terminator, rest) {
+            let (line, next) = rest.split_at(end + 1);
+            if self.partial.is_empty() {
+                self.write_line(line)?;
+            } else {
+                let mut partial = mem::take(&mut self.partial);
+                partial.extend_from_slice(line);
+                self.write_line(&partial)?;
+            }
+            rest = next;
+        }
+        self.partial.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.files.values_mut().try_for_each(Write::flush)
+    }
+}
+
+/// Like [`shard`], but for records terminated by null bytes rather than
+/// newlines, as output by `find -print0`. Keys are extracted from each
+/// record without its terminating `\0`, and a final unterminated record
+/// has one added.
+pub fn shard_nullsep<R, F>(
+    r: R,
+    npartitions: usize,
+    bufsize: usize,
+    key: &Key,
+    mode: ShardMode,
+    options: ShardOptions,
+    f: F,
+) -> ShardStats
+where
+    R: BufRead,
+    F: FnMut(usize, Bytes),
+{
+    shard_records(r, b'\0', npartitions, bufsize, key, mode, options, f)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn shard_records<R, F>(
+    r: R,
+    terminator: u8,
+    npartitions: usize,
+    bufsize: usize,
+    key: &Key,
+    mode: ShardMode,
+    options: ShardOptions,
+    f: F,
+) -> ShardStats
+where
+    R: BufRead,
+    F: FnMut(usize, Bytes),
+{
+    let mut partitioner = Partitioner::new(
+        npartitions,
+        bufsize,
+        options.bufsize_per_partition,
+        key,
+        mode,
+        options.skew_threshold,
+        options.flush_interval,
+        options.line_limit,
+    );
+    partitioner.terminator = terminator;
+    partitioner.window = options.window;
+    partitioner.line_numbers = options.line_numbers;
+    partitioner.set_partition_map(options.partition_map);
+    partition_records(r, partitioner, f)
+}
+
+/// Like [`shard`], but partitions lines by ranges of keys rather than
+/// hashes, so that adjacent keys land in the same partition, e.g., for
+/// range-partitioned databases.
+///
+/// `split_points` must be sorted, and splits up the key space into
+/// `split_points.len() + 1` partitions: partition 0 gets lines with
+/// keys less than `split_points[0]`, partition `i` gets lines with keys
+/// at least `split_points[i - 1]` but less than `split_points[i]`, and the
+/// last partition gets all the rest. Keys are compared bytewise, like
+/// `LC_ALL=C sort`.
+///
+/// [`ShardOptions::partition_map`] is unused.
+pub fn shard_sorted<R, F>(
+    r: R,
+    split_points: &[Vec<u8>],
+    bufsize: usize,
+    key: &Key,
+    options: ShardOptions,
+    f: F,
+) -> ShardStats
+where
+    R: BufRead,
+    F: FnMut(usize, Bytes),
+{
+    debug_assert!(split_points.windows(2).all(|w| w[0] <= w[1]));
+    let mut partitioner = Partitioner::new(
+        split_points.len() + 1,
+        bufsize,
+        options.bufsize_per_partition,
+        key,
+        ShardMode::default(),
+        options.skew_threshold,
+        options.flush_interval,
+        options.line_limit,
+    );
+    partitioner.split_points = Some(split_points);
+    partitioner.window = options.window;
+    partitioner.line_numbers = options.line_numbers;
+    partition_records(r, partitioner, f)
+}
+
+/// Like [`shard`], but rather than filling buffers up to a size, sends
+/// each run of consecutive lines with the same key to `f` as a buffer of
+/// its own as soon as the key changes, e.g., for input grouped by key
+/// where each folder invocation should see a whole group at once.
+///
+/// Runs are still routed by `mode`, so separate runs of one key land in
+/// the same partition under hashing, while under
+/// [`ShardMode::RoundRobin`] runs rather than lines take turns. Keyless
+/// lines form runs of their own. A run is held in memory until it ends,
+/// however long it gets.
+pub fn shard_by_key_run<R, F>(
+    r: R,
+    npartitions: usize,
+    key: &Key,
+    mode: ShardMode,
+    mut f: F,
+) -> ShardStats
+where
+    R: BufRead,
+    F: FnMut(usize, Bytes),
+{
+    let mut partitioner =
+        Partitioner::new(npartitions, usize::MAX, false, key, mode, None, None, None);
+    let mut run_key: Option<Option<Vec<u8>>> = None;
+    let mut run_partition = 0;
     r.for_byte_line_with_terminator(|line| {
-        let key = hash_key(line, npartitions);
-        used_space += line.len();
-        bufs[key].extend_from_slice(line);
-        if used_space >= bufsize {
-            // You might be tempted to ask, why not just send the largest
-            // few buffers to avoid communication overhead? It turns out
-            // this really does not help, at least if we can view
-            // line sizes as constant (or with standard deviation much
-            // smaller than `bufsize`).
-            //
-            // The size of the largest bucket of a hash table with n keys
-            // is lg(n) on average (up to lg(lg(n)) factors). So flushing
-            // the top-k largest buffers at most gets rid of about k*lg(n)
-            // keys. With k set to asymptotically anything less than n
-            // (up to lg(n) factors), we'd be increasing the net number
-            // of flushes (calls to f) we perform.
-            //
-            // Thus, we may as well flush every buffer.
-            for (i, buf) in bufs.iter_mut().enumerate() {
-                if buf.len() > 0 {
-                    f(i, mem::take(buf));
-                }
+        let line_key = match partitioner.observe_key(line) {
+            // Round-robin routing ignores keys, but runs still need them.
+            None if mode == ShardMode::RoundRobin => key.extract(line, b'\n'),
+            line_key => line_key,
+        };
+        if run_key.as_ref().map(Option::as_deref) != Some(line_key.as_deref()) {
+            for (i, buf) in partitioner.flush() {
+                f(i, buf);
             }
-            used_space = 0;
+            run_partition = partitioner.partition(line_key.as_deref());
+            run_key = Some(line_key.map(KeyBytes::into_owned));
+        }
+        for (i, buf) in partitioner.buffer(run_partition, line) {
+            f(i, buf);
         }
         Ok(true)
     })
     .expect("successful byte line read");
-    for (i, buf) in bufs.into_iter().enumerate() {
-        if buf.len() > 0 {
-            f(i, buf)
+    for (i, buf) in partitioner.flush() {
+        f(i, buf);
+    }
+    partitioner.stats
+}
+
+/// Feeds every record of `r` through `partitioner`, calling `f` on
+/// everything it flushes.
+fn partition_records<R, F>(r: R, mut partitioner: Partitioner<'_>, mut f: F) -> ShardStats
+where
+    R: BufRead,
+    F: FnMut(usize, Bytes),
+{
+    let terminator = partitioner.terminator;
+    r.for_byte_record_with_terminator(terminator, |line| {
+        for (i, buf) in partitioner.push(line) {
+            f(i, buf);
+        }
+        Ok(!partitioner.stopped())
+    })
+    .expect("successful byte line read");
+    for (i, buf) in partitioner.finish() {
+        f(i, buf);
+    }
+    partitioner.stats
+}
+
+/// Like [`shard`], but reads from an async reader and awaits `f` on
+/// every flush, so it can run on an async runtime without blocking it.
+#[cfg(feature = "tokio")]
+pub async fn async_shard<R, F>(
+    mut r: R,
+    npartitions: usize,
+    bufsize: usize,
+    key: &Key,
+    mode: ShardMode,
+    options: ShardOptions<'_>,
+    mut f: F,
+) -> ShardStats
+where
+    R: AsyncBufRead + Unpin,
+    F: AsyncFnMut(usize, Bytes),
+{
+    let mut partitioner = Partitioner::new(
+        npartitions,
+        bufsize,
+        options.bufsize_per_partition,
+        key,
+        mode,
+        options.skew_threshold,
+        options.flush_interval,
+        options.line_limit,
+    );
+    partitioner.window = options.window;
+    partitioner.line_numbers = options.line_numbers;
+    partitioner.set_partition_map(options.partition_map);
+    let mut line = Vec::new();
+    while r
+        .read_until(b'\n', &mut line)
+        .await
+        .expect("successful byte line read")
+        > 0
+    {
+        for (i, buf) in partitioner.push(&line) {
+            f(i, buf).await;
+        }
+        if partitioner.stopped() {
+            break;
+        }
+        line.clear();
+    }
+    for (i, buf) in partitioner.finish() {
+        f(i, buf).await;
+    }
+    partitioner.stats
+}
+
+/// The per-partition buffers and bookkeeping behind [`shard`], which is
+/// agnostic to how lines are read and flushed buffers are sent.
+struct Partitioner<'a> {
+    bufs: Vec<BytesMut>,
+    used_space: usize,
+    bufsize: usize,
+    bufsize_per_partition: bool,
+    key: &'a Key,
+    terminator: u8,
+    mode: ShardMode,
+    // overrides `mode`, see `shard_sorted`
+    split_points: Option<&'a [Vec<u8>]>,
+    next_partition: usize,
+    stats: ShardStats,
+    skew: Option<SkewDetector>,
+    flush_interval: Option<Duration>,
+    last_flush: Instant,
+    line_limit: Option<LineLimit>,
+    window: Option<Window>,
+    pending: Option<PendingWindow>,
+    line_numbers: Option<&'a LineNumbers>,
+    partition_map: Option<&'a PartitionMap>,
+}
+
+/// A [`Window`] still being filled.
+struct PendingWindow {
+    key: Option<Vec<u8>>,
+    line: Vec<u8>,
+    nlines: usize,
+}
+
+impl<'a> Partitioner<'a> {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        npartitions: usize,
+        bufsize: usize,
+        bufsize_per_partition: bool,
+        key: &'a Key,
+        mode: ShardMode,
+        skew_threshold: Option<f64>,
+        flush_interval: Option<Duration>,
+        line_limit: Option<LineLimit>,
+    ) -> Self {
+        Self {
+            bufs: vec![BytesMut::new(); npartitions],
+            used_space: 0,
+            bufsize,
+            bufsize_per_partition,
+            key,
+            terminator: b'\n',
+            mode,
+            split_points: None,
+            next_partition: 0,
+            stats: ShardStats {
+                partition_lines: vec![0; npartitions],
+                partition_bytes: vec![0; npartitions],
+                ..ShardStats::default()
+            },
+            skew: skew_threshold.map(SkewDetector::new),
+            flush_interval,
+            last_flush: Instant::now(),
+            line_limit,
+            window: None,
+            pending: None,
+            line_numbers: None,
+            partition_map: None,
+        }
+    }
+
+    fn set_partition_map(&mut self, partition_map: Option<&'a PartitionMap>) {
+        if let Some((key, &partition)) = partition_map
+            .into_iter()
+            .flatten()
+            .find(|(_, &partition)| partition >= self.bufs.len())
+        {
+            panic!(
+                "key {:?} mapped to partition {} out of {}",
+                String::from_utf8_lossy(key),
+                partition,
+                self.bufs.len()
+            );
+        }
+        self.partition_map = partition_map;
+    }
+
+    /// Whether a line over the limit has stopped reading.
+    fn stopped(&self) -> bool {
+        self.stats.long_lines > 0
+            && self
+                .line_limit
+                .is_some_and(|limit| limit.policy == LongLines::Error)
+    }
+
+    /// Buffers a line, returning any buffers it's time to flush.
+    fn push(&mut self, line: &[u8]) -> Vec<(usize, Bytes)> {
+        let truncated;
+        let line = match self.line_limit {
+            Some(limit) => {
+                let content = line.strip_suffix(&[self.terminator]).unwrap_or(line);
+                if content.len() <= limit.max_len {
+                    line
+                } else {
+                    self.stats.long_lines += 1;
+                    match limit.policy {
+                        LongLines::Truncate => {
+                            truncated = [&content[..limit.max_len], &[self.terminator]].concat();
+                            &truncated
+                        }
+                        LongLines::Error | LongLines::Skip => return Vec::new(),
+                    }
+                }
+            }
+            None => line,
+        };
+        let key = self.observe_key(line);
+        let window = match self.window {
+            Some(window) => window,
+            None => {
+                let partition = self.partition(key.as_deref());
+                return self.buffer(partition, line);
+            }
+        };
+        let mut flushed = match &self.pending {
+            Some(pending) if pending.key.as_deref() != key.as_deref() => self.finish_window(),
+            _ => Vec::new(),
+        };
+        let pending = self.pending.get_or_insert_with(|| PendingWindow {
+            key: key.map(KeyBytes::into_owned),
+            line: Vec::new(),
+            nlines: 0,
+        });
+        let content = line.strip_suffix(&[self.terminator]).unwrap_or(line);
+        match window.format {
+            WindowFormat::Tab => {
+                if pending.nlines > 0 {
+                    pending.line.push(b'\t');
+                }
+                pending.line.extend_from_slice(content);
+            }
+            WindowFormat::Json => {
+                pending
+                    .line
+                    .push(if pending.nlines > 0 { b',' } else { b'[' });
+                serde_json::to_writer(&mut pending.line, &String::from_utf8_lossy(content))
+                    .expect("write to vec");
+            }
+        }
+        pending.nlines += 1;
+        if pending.nlines >= window.size {
+            flushed.extend(self.finish_window());
+        }
+        flushed
+    }
+
+    /// Extracts the key of a line, noting it in the stats, or returns
+    /// `None` if it has none or keys are unused.
+    fn observe_key<'l>(&mut self, line: &'l [u8]) -> Option<KeyBytes<'l>> {
+        if self.mode == ShardMode::RoundRobin && self.split_points.is_none() {
+            return None;
+        }
+        match self.key.extract(line, self.terminator) {
+            Some(bytes) => {
+                self.stats.distinct_keys.insert(&bytes);
+                if let Some(skew) = &mut self.skew {
+                    skew.observe(&bytes);
+                }
+                Some(bytes)
+            }
+            None => {
+                self.stats.keyless_lines += 1;
+                None
+            }
+        }
+    }
+
+    /// Picks the partition of the next line routed, with the given key.
+    fn partition(&mut self, key: Option<&[u8]>) -> usize {
+        let npartitions = self.bufs.len();
+        match (self.mode, key) {
+            (ShardMode::RoundRobin, _) if self.split_points.is_none() => {
+                let partition = self.next_partition;
+                self.next_partition = (self.next_partition + 1) % npartitions;
+                partition
+            }
+            (_, Some(key)) => route(
+                key,
+                npartitions,
+                self.mode,
+                self.split_points,
+                self.partition_map,
+            ),
+            (_, None) => 0,
+        }
+    }
+
+    /// Routes the pending window, if any, returning any buffers it's time
+    /// to flush.
+    fn finish_window(&mut self) -> Vec<(usize, Bytes)> {
+        let mut pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Vec::new(),
+        };
+        if self.window.map(|window| window.format) == Some(WindowFormat::Json) {
+            pending.line.push(b']');
+        }
+        pending.line.push(self.terminator);
+        let partition = self.partition(pending.key.as_deref());
+        self.buffer(partition, &pending.line)
+    }
+
+    /// Routes what's left at the end of input, returning every remaining
+    /// buffer.
+    fn finish(&mut self) -> Vec<(usize, Bytes)> {
+        let mut flushed = self.finish_window();
+        flushed.extend(self.flush());
+        flushed
+    }
+
+    /// Buffers a line for the given partition, returning any buffers it's
+    /// time to flush.
+    fn buffer(&mut self, key: usize, line: &[u8]) -> Vec<(usize, Bytes)> {
+        // The last line of input may be unterminated, but it mustn't run
+        // into the next line in the partition's buffer.
+        let terminated = line.last() == Some(&self.terminator);
+        let start = self.bufs[key].len();
+        if let Some(numbers) = self.line_numbers {
+            let number = numbers.counter.fetch_add(1, Ordering::Relaxed) + 1;
+            let prefix = match numbers.source {
+                Some(source) => format!("{}:{}\t", source, number),
+                None => format!("{}\t", number),
+            };
+            self.bufs[key].extend_from_slice(prefix.as_bytes());
+        }
+        self.bufs[key].extend_from_slice(line);
+        if !terminated {
+            self.bufs[key].extend_from_slice(&[self.terminator]);
+        }
+        let len = self.bufs[key].len() - start;
+        self.used_space += len;
+        self.stats.lines += 1;
+        self.stats.bytes += len;
+        self.stats.partition_lines[key] += 1;
+        self.stats.partition_bytes[key] += len;
+        let stale = match self.flush_interval {
+            Some(interval) if self.stats.lines.is_multiple_of(CLOCK_CHECK_LINES) => {
+                self.last_flush.elapsed() >= interval
+            }
+            _ => false,
+        };
+        if self.bufsize_per_partition && self.bufs[key].len() >= self.bufsize {
+            let buf = self.bufs[key].split().freeze();
+            self.used_space -= buf.len();
+            self.flushed(1);
+            vec![(key, buf)]
+        } else if (!self.bufsize_per_partition && self.used_space >= self.bufsize) || stale {
+            self.flush()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Takes every non-empty buffer, tagged with its partition index.
+    fn flush(&mut self) -> Vec<(usize, Bytes)> {
+        // You might be tempted to ask, why not just send the largest
+        // few buffers to avoid communication overhead? It turns out
+        // this really does not help, at least if we can view
+        // line sizes as constant (or with standard deviation much
+        // smaller than `bufsize`).
+        //
+        // The size of the largest bucket of a hash table with n keys
+        // is lg(n) on average (up to lg(lg(n)) factors). So flushing
+        // the top-k largest buffers at most gets rid of about k*lg(n)
+        // keys. With k set to asymptotically anything less than n
+        // (up to lg(n) factors), we'd be increasing the net number
+        // of flushes (calls to f) we perform.
+        //
+        // Thus, we may as well flush every buffer.
+        let flushed: Vec<_> = self
+            .bufs
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, buf)| !buf.is_empty())
+            .map(|(i, buf)| (i, buf.split().freeze()))
+            .collect();
+        self.used_space = 0;
+        if self.flush_interval.is_some() {
+            self.last_flush = Instant::now();
+        }
+        self.flushed(flushed.len());
+        flushed
+    }
+
+    fn flushed(&mut self, nbufs: usize) {
+        self.stats.flushes += nbufs;
+        if let Some(skew) = &mut self.skew {
+            skew.check(&self.stats.partition_lines);
+        }
+    }
+}
+
+/// Tracks key frequencies to report when partitions become skewed.
+struct SkewDetector {
+    threshold: f64,
+    key_counts: BTreeMap<Vec<u8>, usize>,
+    warned: bool,
+}
+
+impl SkewDetector {
+    /// Only this many distinct key prefixes are tracked, first come,
+    /// first served, to bound memory.
+    const MAX_KEYS: usize = 1000;
+    const KEY_PREFIX_LEN: usize = 64;
+    const TOP_KEYS: usize = 3;
+
+    fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            key_counts: BTreeMap::new(),
+            warned: false,
+        }
+    }
+
+    fn observe(&mut self, key: &[u8]) {
+        if self.warned {
+            return;
+        }
+        let prefix = &key[..key.len().min(Self::KEY_PREFIX_LEN)];
+        if let Some(count) = self.key_counts.get_mut(prefix) {
+            *count += 1;
+        } else if self.key_counts.len() < Self::MAX_KEYS {
+            self.key_counts.insert(prefix.to_vec(), 1);
         }
     }
+
+    fn check(&mut self, partition_lines: &[usize]) {
+        if self.warned {
+            return;
+        }
+        let n = partition_lines.len() as f64;
+        let mean = partition_lines.iter().sum::<usize>() as f64 / n;
+        if mean == 0.0 {
+            return;
+        }
+        let var = partition_lines
+            .iter()
+            .map(|&lines| (lines as f64 - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        let cv = var.sqrt() / mean;
+        if cv <= self.threshold {
+            return;
+        }
+        let mut top: Vec<_> = self.key_counts.iter().collect();
+        top.sort_by(|a, b| b.1.cmp(a.1));
+        let top: Vec<_> = top
+            .into_iter()
+            .take(Self::TOP_KEYS)
+            .map(|(key, count)| format!("{:?} ({})", String::from_utf8_lossy(key), count))
+            .collect();
+        eprintln!(
+            "warning: skewed partitions (line count coefficient of variation {:.2} > {}), \
+             most frequent keys: {}",
+            cv,
+            self.threshold,
+            top.join(", ")
+        );
+        self.warned = true;
+        self.key_counts.clear();
+    }
+}
+
+/// Returns which of `npartitions` partitions [`shard`], or with
+/// `split_points`, [`shard_sorted`], sends `line` to, for checking where
+/// lines ended up after the fact. The `terminator` is `b'\n'`, or `b'\0'`
+/// as for [`shard_nullsep`]. Lines without a key go to partition 0.
+///
+/// Returns `None` in [`ShardMode::RoundRobin`], where the partition
+/// doesn't depend on the line; `mode` is ignored with `split_points`, and
+/// both are for keys not in the `partition_map`.
+pub fn partition_of(
+    line: &[u8],
+    terminator: u8,
+    npartitions: usize,
+    key: &Key,
+    mode: ShardMode,
+    split_points: Option<&[Vec<u8>]>,
+    partition_map: Option<&PartitionMap>,
+) -> Option<usize> {
+    if mode == ShardMode::RoundRobin && split_points.is_none() {
+        return None;
+    }
+    Some(match key.extract(line, terminator) {
+        Some(bytes) => route(&bytes, npartitions, mode, split_points, partition_map),
+        None => 0,
+    })
+}
+
+/// The partition of a line with the given key, see [`partition_of`].
+fn route(
+    key: &[u8],
+    npartitions: usize,
+    mode: ShardMode,
+    split_points: Option<&[Vec<u8>]>,
+    partition_map: Option<&PartitionMap>,
+) -> usize {
+    if let Some(&partition) = partition_map.and_then(|map| map.get(key)) {
+        return partition;
+    }
+    match (split_points, mode) {
+        (Some(points), _) => points.partition_point(|point| point.as_slice() <= key),
+        (None, ShardMode::Hash(hasher)) => hash_key(key, npartitions.try_into().unwrap(), hasher),
+        (None, ShardMode::ConsistentHash(hasher)) => hasher.partition(key, npartitions),
+        (None, ShardMode::RoundRobin) => unreachable!("round robin ignores keys"),
+    }
+}
+
+/// Hashes `key` into one of `npartitions`, as [`ShardMode::Hash`] with
+/// `hasher` does. Panics if `npartitions` is zero.
+///
+/// An empty key is hashed like any other, so lines with one all go to the
+/// same partition: with [`Key::Delimited`], these are the lines starting
+/// with the delimiter, e.g., lines of nothing but spaces by default.
+#[inline]
+pub fn hash_key(key: &[u8], npartitions: u64, hasher: HashFn) -> usize {
+    (hasher.hash(key) % npartitions) as usize
 }
 
-fn hash_key(bytes: &[u8], npartitions: u64) -> usize {
-    let end = memchr(b' ', bytes).unwrap_or(bytes.len());
-    // TODO: consider faster hasher?
-    let mut hasher = DefaultHasher::default();
-    bytes[..end].hash(&mut hasher);
-    (hasher.finish() % npartitions) as usize
+/// Returns which of `npartitions` partitions the default [`ShardMode`]
+/// sends lines with the (already extracted) `key` to, e.g., to check that
+/// a pre-sharded dataset is partitioned the way `slb` would. Panics if
+/// `npartitions` is zero.
+#[inline(always)]
+pub fn partition_for_key(key: &[u8], npartitions: usize) -> usize {
+    hash_key(key, npartitions as u64, HashFn::default())
 }
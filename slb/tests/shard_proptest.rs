@@ -0,0 +1,54 @@
+//! Property tests of [`sharder::shard`] on arbitrary bytes.
+
+use bytes::Bytes;
+use proptest::prelude::*;
+
+use slb::sharder::{self, HashFn, Key, ShardMode, ShardOptions};
+
+proptest! {
+    #[test]
+    fn shard_routes_every_line_once(
+        input in proptest::collection::vec(
+            prop_oneof![3 => any::<u8>(), 1 => Just(b'\n'), 1 => Just(b' ')],
+            0..10000,
+        ),
+        npartitions in 1usize..8,
+        bufsize in 1usize..512,
+        bufsize_per_partition in any::<bool>(),
+    ) {
+        let key = Key::default();
+        let mode = ShardMode::Hash(HashFn::default());
+        let mut outputs = vec![Vec::new(); npartitions];
+        let mut empty_buffers = 0;
+        let stats = sharder::shard(
+            &input[..],
+            npartitions,
+            bufsize,
+            &key,
+            mode,
+            ShardOptions {
+                bufsize_per_partition,
+                ..ShardOptions::default()
+            },
+            |ix, buf: Bytes| {
+                empty_buffers += buf.is_empty() as usize;
+                outputs[ix].extend_from_slice(&buf);
+            },
+        );
+        prop_assert_eq!(empty_buffers, 0);
+
+        // each partition has exactly its lines, in input order, with a
+        // newline added to an unterminated last line
+        let mut expected = vec![Vec::new(); npartitions];
+        for line in input.split_inclusive(|&b| b == b'\n') {
+            let ix = sharder::partition_of(line, b'\n', npartitions, &key, mode, None, None).unwrap();
+            expected[ix].extend_from_slice(line);
+            if !line.ends_with(b"\n") {
+                expected[ix].push(b'\n');
+            }
+        }
+        let expected_bytes: usize = expected.iter().map(Vec::len).sum();
+        prop_assert_eq!(stats.bytes, expected_bytes);
+        prop_assert_eq!(outputs, expected);
+    }
+}
@@ -48,5 +48,891 @@ for f in $testfiles ; do
     rm actual-split-${b}.*
     sort -k2nr -k1 -o "actual-split-$b" "actual-split-$b"
     diff "actual-$b" "actual-split-$b" >/dev/null
+
+    "$cwd/target/release/slb" \
+        --mapper 'tr "[:space:]" "\n" | rg -v "^$"' \
+        --folder "awk '{a[\$0]++}END{for(k in a)print k,a[k]}'" \
+        --outprefix "actual-stdin-$b." < "$f"
+    cat actual-stdin-${b}.* > "actual-stdin-$b"
+    rm actual-stdin-${b}.*
+    sort -k2nr -k1 -o "actual-stdin-$b" "actual-stdin-$b"
+    diff "actual-$b" "actual-stdin-$b" >/dev/null
 done 
+
+echo "testing input without trailing newlines"
+printf 'a 1\nb 2' > nonl-1.txt
+printf 'c 3\nd 4' > nonl-2.txt
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --infile nonl-*.txt \
+    --outprefix "actual-nonl."
+cat actual-nonl.* | sort > actual-nonl
+rm actual-nonl.*
+printf 'a 1\nb 2\nc 3\nd 4\n' | diff - actual-nonl >/dev/null
+# the unterminated last line has the same key as the earlier one
+printf 'x\ny\nz\nw\nq\nx' > nonl.txt
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --nfolders 4 \
+    --infile nonl.txt \
+    --outprefix "actual-nonl."
+test "$(grep -lx x actual-nonl.* | wc -l)" -eq 1
+rm actual-nonl.* nonl.txt
+
+echo "testing chunk allocation across 1K, 10K, and 100K files"
+mkdir sized
+for kb in 1 10 100 ; do
+    head -c $((kb * 1024)) "$cwd/examples/bible.txt" > "sized/$kb.txt"
+done
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --nthreads 8 \
+    --indir sized \
+    --outprefix "actual-sized."
+# both small files are below the 16K minimum chunk size, while the
+# 100K file gets the remaining budget, capped by that minimum at 6
+nchunks=$(ls actual-sized.* | wc -l)
+test "$nchunks" -eq 8
+# per file, the 1K one gets exactly one chunk, and the rest get chunks in
+# proportion to their size, so with a budget of 2 the 100K file gets both
+# while the small files still get one each
+for nthreads in 2 8 ; do
+    "$cwd/target/release/slb" \
+        --folder "cat" \
+        --nthreads $nthreads \
+        --indir sized \
+        --outprefix "actual-sized." \
+        --dry-run > actual-sized-plan
+    test "$(grep -c 'sized/1\.txt ' actual-sized-plan)" -eq 1
+    test "$(grep -c 'sized/10\.txt ' actual-sized-plan)" -eq 1
+    test "$(grep -c 'sized/100\.txt ' actual-sized-plan)" -eq $((nthreads == 2 ? 2 : 6))
+done
+rm actual-sized-plan
+cat actual-sized.* | sort > actual-sized
+rm actual-sized.*
+for f in sized/* ; do cat "$f" ; echo ; done | sort | diff - actual-sized >/dev/null
+
+echo "testing null-terminated records"
+printf 'a 1\nx\0b 2\0a 3\0b 4' > null.txt
+"$cwd/target/release/slb" \
+    --null \
+    --folder "tr '\\0\\n' '\\n_'" \
+    --infile null.txt \
+    --outprefix "actual-null."
+cat actual-null.* | sort > actual-null
+rm actual-null.*
+printf 'a 1_x\na 3\nb 2\nb 4\n' | diff - actual-null >/dev/null
+
+echo "testing combiners"
+sum="awk '{a[\$1]+=\$2}END{for(k in a)print k,a[k]}'"
+"$cwd/target/release/slb" \
+    --mapper 'tr "[:space:]" "\n" | rg -v "^$" | sed "s/$/ 1/"' \
+    --combiner "$sum" \
+    --folder "$sum" \
+    --infile "$cwd/examples/bible.txt" \
+    --outprefix "actual-combined."
+cat actual-combined.* | sort -k2nr -k1 > actual-combined
+rm actual-combined.*
+diff expected-bible.txt actual-combined >/dev/null
+
+echo "testing compressed output"
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --infile nonl-1.txt \
+    --infile nonl-2.txt \
+    --nfolders 2 \
+    --output-compression gzip \
+    --outprefix "actual-gz."
+test -f actual-gz.0.gz
+zcat actual-gz.*.gz | sort > actual-gz
+rm actual-gz.*
+printf 'a 1\nb 2\nc 3\nd 4\n' | diff - actual-gz >/dev/null
+
+echo "testing folder output to stdout"
+"$cwd/target/release/slb" \
+    --folder "sort" \
+    --infile nonl-1.txt \
+    --infile nonl-2.txt > actual-stdout
+printf 'a 1\nb 2\nc 3\nd 4\n' | diff - actual-stdout >/dev/null
+
+echo "testing child environments"
+"$cwd/target/release/slb" \
+    --clear-env \
+    --env GREETING=hi \
+    --mapper 'sed "s/^/$GREETING /"' \
+    --folder 'echo "[$HOME]" ; cat' \
+    --infile nonl-1.txt > actual-env
+printf '[]\nhi a 1\nhi b 2\n' | diff - actual-env >/dev/null
+
+echo "testing shells"
+"$cwd/target/release/slb" \
+    --shell /bin/sh \
+    --folder 'cat >/dev/null ; echo "$0"' \
+    --infile nonl-1.txt > actual-shell
+echo /bin/sh | diff - actual-shell >/dev/null
+
+echo "testing long lines"
+printf 'a 1\nbbbbbbbbbb 2\nc 3\n' > long.txt
+"$cwd/target/release/slb" \
+    --max-line-length 5 \
+    --on-long-line truncate \
+    --folder "sort" \
+    --infile long.txt > actual-long 2>/dev/null
+printf 'a 1\nbbbbb\nc 3\n' | diff - actual-long >/dev/null
+if "$cwd/target/release/slb" \
+    --max-line-length 5 \
+    --folder "cat" \
+    --infile long.txt \
+    --outprefix "actual-long." 2>/dev/null ; then
+    echo "expected failure"
+    exit 1
+fi
+
+echo "testing presorted folder input"
+printf 'b 1\na 2\nb 3\na 4\n' > unsorted.txt
+"$cwd/target/release/slb" \
+    --presort \
+    --folder "awk '\$1 != k {print \$1} {k = \$1}'" \
+    --infile unsorted.txt > actual-presort
+printf 'a\nb\n' | diff - actual-presort >/dev/null
+
+echo "testing pre-partitioned input"
+printf 'x 1\ny 2\nz 3\n' > partitioned.txt
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --nfolders 2 \
+    --infile nonl-1.txt \
+    --infile-partition 1:partitioned.txt \
+    --outprefix "actual-partition."
+grep -v '^[ab] ' actual-partition.1 | diff partitioned.txt - >/dev/null
+test -z "$(grep '^[xyz] ' actual-partition.0)"
+rm actual-partition.*
+
+echo "testing range partitioning"
+printf 'c\nm\n' > split-points.txt
+printf 'a 1\nz 2\nc 3\nd 4\nm 5\n' > ranged.txt
+"$cwd/target/release/slb" \
+    --folder "sort" \
+    --split-points-file split-points.txt \
+    --infile ranged.txt \
+    --outprefix "actual-ranged."
+printf 'a 1\n' | diff - actual-ranged.0 >/dev/null
+printf 'c 3\nd 4\n' | diff - actual-ranged.1 >/dev/null
+printf 'm 5\nz 2\n' | diff - actual-ranged.2 >/dev/null
+rm actual-ranged.*
+
+echo "testing input line filters"
+printf '# header\nb 1\n# note\na 2\n' > commented.txt
+"$cwd/target/release/slb" \
+    --folder "sort" \
+    --input-line-filter '^#' \
+    --infile commented.txt > actual-filter
+printf 'a 2\nb 1\n' | diff - actual-filter >/dev/null
+"$cwd/target/release/slb" \
+    --folder "sort" \
+    --input-line-filter '^#' \
+    --input-line-filter-invert \
+    --infile commented.txt > actual-filter
+printf '# header\n# note\n' | diff - actual-filter >/dev/null
+
+echo "testing input sampling"
+seq 1000 > sampled.txt
+"$cwd/target/release/slb" \
+    --folder "sort" \
+    --sample-rate 0.1 \
+    --seed 1 \
+    --infile sampled.txt > actual-sample-1
+"$cwd/target/release/slb" \
+    --folder "sort" \
+    --sample-rate 0.1 \
+    --seed 1 \
+    --infile sampled.txt > actual-sample-2
+diff actual-sample-1 actual-sample-2 >/dev/null
+test "$(wc -l < actual-sample-1)" -lt 1000
+"$cwd/target/release/slb" \
+    --folder "wc -l" \
+    --sample-rate 1 \
+    --infile sampled.txt | grep -q '^1000$'
+
+echo "testing cardinality estimates"
+printf 'a 1\nb 2\na 3\n' > repeated.txt
+"$cwd/target/release/slb" \
+    --folder "cat >/dev/null" \
+    --estimate-cardinality \
+    --infile repeated.txt 2>&1 | grep -q '^~2 distinct keys in 3 lines (12 bytes)$'
+
+echo "testing mapper workers"
+seq 100000 > workers.txt
+"$cwd/target/release/slb" \
+    --mapper "cat" \
+    --mapper-workers 3 \
+    --folder "sort -n" \
+    --infile workers.txt > actual-workers
+diff workers.txt actual-workers >/dev/null
+"$cwd/target/release/slb" \
+    --mapper "printf x" \
+    --mapper-workers 3 \
+    --folder "cat" \
+    --infile workers.txt > actual-workers
+printf 'x\nx\nx\n' | diff - actual-workers >/dev/null
+
+echo "testing merged output"
+"$cwd/target/release/slb" \
+    --folder "sort" \
+    --nfolders 3 \
+    --infile nonl-1.txt \
+    --outprefix "actual-unmerged."
+"$cwd/target/release/slb" \
+    --folder "sort" \
+    --nfolders 3 \
+    --infile nonl-1.txt \
+    --merge-output actual-merged
+cat actual-unmerged.* | diff - actual-merged >/dev/null
+rm actual-unmerged.*
+
+echo "testing stats files"
+"$cwd/target/release/slb" \
+    --folder "cat >/dev/null" \
+    --nthreads 4 \
+    --infile repeated.txt \
+    --stats-file actual-stats.json
+grep -q '"total_lines_sent":3,' actual-stats.json
+# the --nthreads asked for, though there's only one chunk to map
+grep -q '"nthreads":4,' actual-stats.json
+grep -q '"nchunks":1,' actual-stats.json
+grep -q '"per_partition_bytes":\[12\],' actual-stats.json
+
+echo "testing socket folders"
+for f in nonl-1.txt repeated.txt ; do
+    "$cwd/target/release/slb" \
+        --folder "wc -l" \
+        --nfolders 1 \
+        --socket-folder \
+        --infile "$f" \
+        --outprefix "actual-socket."
+done
+pkill -f "serve-folder actual-socket.0.sock"
+for i in $(seq 50) ; do test -s actual-socket.0 && break ; sleep 0.1 ; done
+test "$(cat actual-socket.0)" -eq 5
+rm actual-socket.*
+
+echo "testing key transforms"
+printf 'Apple 1\napple 2\nAPPLE 3\n' > cased.txt
+"$cwd/target/release/slb" \
+    --folder "wc -l" \
+    --nfolders 8 \
+    --key-transform lowercase \
+    --infile cased.txt \
+    --outprefix "actual-transform."
+test "$(cat actual-transform.* | sort -n | tail -1)" -eq 3
+rm actual-transform.*
+
+echo "testing output verification"
+"$cwd/target/release/slb" \
+    --folder "sort" \
+    --nfolders 4 \
+    --verify \
+    --infile cased.txt \
+    --outprefix "actual-verify."
+if "$cwd/target/release/slb" \
+    --folder "cat ; echo extra" \
+    --nfolders 4 \
+    --verify \
+    --infile cased.txt \
+    --outprefix "actual-verify." 2>/dev/null ; then
+    echo "misrouted lines passed verification"
+    exit 1
+fi
+rm actual-verify.*
+# output of an unterminated last line gains a newline, but keeps its folder
+printf 'x\ny\nz\nw\nq\nx' > nonl.txt
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --nfolders 4 \
+    --verify \
+    --infile nonl.txt \
+    --outprefix "actual-verify."
+rm actual-verify.* nonl.txt
+
+echo "testing benchmarks"
+"$cwd/target/release/slb" \
+    --benchmark \
+    --bench-nlines 1000 \
+    --nfolders 2 | grep -q '^sharded 1000 lines (64000 bytes) into 2 partitions'
+
+echo "testing checksums"
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --infile repeated.txt \
+    --nfolders 2 \
+    --outprefix "actual-checksum." \
+    --checksum md5 \
+    --output-compression gzip > actual-checksums.md5
+test "$(wc -l < actual-checksums.md5)" -eq 2
+md5sum --quiet -c actual-checksums.md5
+rm actual-checksum.* actual-checksums.md5
+
+echo "testing windows"
+printf 'a 1\na 2\na 3\nb 1\na 4\n' > windowed.txt
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --infile windowed.txt \
+    --window 2 > actual-windows.txt
+printf 'a 1\ta 2\na 3\nb 1\na 4\n' | diff - actual-windows.txt
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --infile windowed.txt \
+    --window 3 \
+    --window-format json > actual-windows.txt
+printf '["a 1","a 2","a 3"]\n["b 1"]\n["a 4"]\n' | diff - actual-windows.txt
+rm windowed.txt actual-windows.txt
+
+echo "testing skipped headers"
+{ echo "key,value" ; seq 1 100000 | sed 's/$/,x/' ; } > with-header.csv
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --infile with-header.csv \
+    --nthreads 4 \
+    --input-skip-header \
+    --header-output actual-header.csv > actual-no-header.csv
+test "$(cat actual-header.csv)" = "key,value"
+test "$(wc -l < actual-no-header.csv)" -eq 100000
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --infile with-header.csv \
+    --nthreads 4 \
+    --input-skip-lines 50001 > actual-no-header.csv
+test "$(wc -l < actual-no-header.csv)" -eq 50000
+rm with-header.csv actual-header.csv actual-no-header.csv
+
+echo "testing folder stdin prefixes and suffixes"
+printf 'count\n' > prefix.txt
+for prefix in 636f756e740a @prefix.txt ; do
+    "$cwd/target/release/slb" \
+        --folder "cat" \
+        --infile repeated.txt \
+        --folder-stdin-prefix "$prefix" > actual-prefixed.txt
+    test "$(head -1 actual-prefixed.txt)" = "count"
+done
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --infile repeated.txt \
+    --folder-stdin-suffix @prefix.txt > actual-prefixed.txt
+test "$(tail -1 actual-prefixed.txt)" = "count"
+rm prefix.txt actual-prefixed.txt
+
+echo "testing output rotation"
+seq 1 1000 > counted.txt
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --infile counted.txt \
+    --outprefix "actual-rotated." \
+    --max-output-lines 300
+test "$(wc -l < actual-rotated.0_3)" -eq 100
+cat actual-rotated.* | sort -n | diff - counted.txt
+rm actual-rotated.*
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --infile counted.txt \
+    --outprefix "actual-rotated." \
+    --max-output-bytes 1000
+for f in actual-rotated.* ; do test "$(wc -c < "$f")" -le 1000 ; done
+cat actual-rotated.* | sort -n | diff - counted.txt
+rm actual-rotated.* counted.txt
+
+echo "testing named pipe input"
+"$cwd/target/release/slb" \
+    --folder "wc -l" \
+    --infile <(seq 1 1000) \
+    --infile <(seq 1001 2000) > actual-piped.txt
+test "$(cat actual-piped.txt)" -eq 2000
+"$cwd/target/release/slb" \
+    --folder "wc -l" \
+    --infile <(seq 1 1000) \
+    --input-skip-header > actual-piped.txt
+test "$(cat actual-piped.txt)" -eq 999
+rm actual-piped.txt
+
+echo "testing progress bars off a terminal"
+"$cwd/target/release/slb" \
+    --folder "wc -l" \
+    --infile repeated.txt \
+    --progress-bar > actual-progress.txt 2> actual-progress-bar.txt
+test "$(cat actual-progress.txt)" -eq 3
+test ! -s actual-progress-bar.txt
+rm actual-progress.txt actual-progress-bar.txt
+
+echo "testing output headers"
+printf 'key\n' > header.txt
+seq 1 1000 > counted.txt
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --infile counted.txt \
+    --nfolders 3 \
+    --outprefix "actual-headed." \
+    --output-header-file header.txt \
+    --max-output-lines 300
+for f in actual-headed.* ; do test "$(head -1 "$f")" = "key" ; done
+tail -q -n +2 actual-headed.* | sort -n | diff - counted.txt
+rm actual-headed.* header.txt counted.txt
+
+echo "testing folder output to a merged stdout"
+seq 1 1000 > counted.txt
+"$cwd/target/release/slb" \
+    --folder "sort -n" \
+    --nfolders 3 \
+    --infile counted.txt \
+    --outprefix "actual-unmerged."
+"$cwd/target/release/slb" \
+    --folder "sort -n" \
+    --nfolders 3 \
+    --infile counted.txt \
+    --folder-output-to-stdout | cat > actual-merged-stdout
+cat actual-unmerged.* | diff - actual-merged-stdout >/dev/null
+rm actual-unmerged.* actual-merged-stdout counted.txt
+
+echo "testing mapper output passthrough"
+seq 1 100000 > counted.txt
+"$cwd/target/release/slb" \
+    --mapper "sed 's/^/x/'" \
+    --folder "cat" \
+    --nthreads 4 \
+    --infile counted.txt \
+    --mapper-output-passthrough \
+    --outprefix "actual-passthrough."
+test "$(ls actual-passthrough.* | wc -l)" -eq 4
+cat actual-passthrough.* | sed 's/^x//' | diff - counted.txt >/dev/null
+rm actual-passthrough.* counted.txt
+
+echo "testing line number prefixes"
+seq 1 1000 > counted.txt
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --nfolders 3 \
+    --infile counted.txt \
+    --outprefix "actual-unnumbered."
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --nfolders 3 \
+    --infile counted.txt \
+    --line-number-prefix \
+    --outprefix "actual-numbered."
+for i in 0 1 2 ; do
+    cut -f2 "actual-numbered.$i" | diff - "actual-unnumbered.$i" >/dev/null
+done
+cat actual-numbered.* | cut -f1 | sort -n | diff - counted.txt >/dev/null
+rm actual-unnumbered.* actual-numbered.* counted.txt
+
+echo "testing mapper batches"
+seq 1 1000 > counted.txt
+"$cwd/target/release/slb" \
+    --mapper "wc -l" \
+    --folder "sort | uniq -c" \
+    --batch-size 300 \
+    --infile counted.txt > actual-batches
+printf '      1 100\n      3 300\n' | diff - actual-batches >/dev/null
+rm actual-batches counted.txt
+
+echo "testing hot reloads"
+echo "sed 's/^/new /'" > reload.txt
+mkfifo reloaded.fifo
+"$cwd/target/release/slb" \
+    --folder "sed 's/^/old /'" \
+    --nfolders 2 \
+    --flush-interval 0 \
+    --hot-reload \
+    --hot-reload-config reload.txt \
+    --infile reloaded.fifo \
+    --outprefix "actual-reloaded." 2>/dev/null &
+slb_pid=$!
+exec 3>reloaded.fifo
+seq 1 3000 >&3
+sleep 1
+kill -USR1 "$slb_pid"
+sleep 0.5
+seq 3001 6000 >&3
+exec 3>&-
+wait "$slb_pid"
+test "$(cat actual-reloaded.* | grep -c '^old ')" -eq 3000
+test "$(cat actual-reloaded.* | grep -c '^new ')" -eq 3000
+rm actual-reloaded.* reloaded.fifo reload.txt
+
+echo "testing multiple folders"
+seq 1 1000 > counted.txt
+"$cwd/target/release/slb" \
+    --folder "wc -l" \
+    --folder "cat" \
+    --nfolders 2 \
+    --infile counted.txt \
+    --outprefix "actual-fanout."
+test "$(cat actual-fanout.*_folder0 | awk '{s += $1} END {print s}')" -eq 1000
+cat actual-fanout.*_folder1 | sort -n | diff - counted.txt >/dev/null
+rm actual-fanout.* counted.txt
+
+echo "testing mapper failures"
+seq 1 1000 > counted.txt
+"$cwd/target/release/slb" \
+    --mapper "cat ; exit 1" \
+    --folder "wc -l" \
+    --on-mapper-failure skip \
+    --infile counted.txt > actual-skipped 2>/dev/null
+test "$(cat actual-skipped)" -eq 0
+# fails twice, then succeeds
+echo 0 > attempts.txt
+"$cwd/target/release/slb" \
+    --mapper 'n=$(cat attempts.txt) ; echo $((n + 1)) > attempts.txt ; cat ; test "$n" -ge 2' \
+    --folder "wc -l" \
+    --on-mapper-failure retry \
+    --infile counted.txt > actual-retried 2>/dev/null
+test "$(cat actual-retried)" -eq 1000
+test "$(cat attempts.txt)" -eq 3
+rm actual-skipped actual-retried attempts.txt counted.txt
+
+echo "testing input shuffles"
+seq 1 1000 > ordered.txt
+for i in 1 2 ; do
+    "$cwd/target/release/slb" \
+        --mapper "head -n 5" \
+        --folder "cat" \
+        --input-shuffle \
+        --shuffle-seed 5 \
+        --infile ordered.txt > "actual-shuffled-$i"
+done
+cmp actual-shuffled-1 actual-shuffled-2
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --input-shuffle \
+    --infile ordered.txt | sort -n > actual-shuffled
+cmp actual-shuffled ordered.txt
+rm actual-shuffled actual-shuffled-1 actual-shuffled-2 ordered.txt
+
+echo "testing partition maps"
+for i in $(seq 1 100) ; do
+    echo "key$i value"
+    echo "hot value$i"
+done > keyed.txt
+printf 'hot 2\nkey1 0\n' > partition-map.txt
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --nfolders 3 \
+    --partition-map-file partition-map.txt \
+    --verify \
+    --infile keyed.txt \
+    --outprefix "actual-mapped."
+test "$(grep -c hot actual-mapped.2)" -eq 100
+test -z "$(grep hot actual-mapped.0 actual-mapped.1)"
+grep -qx "key1 value" actual-mapped.0
+rm actual-mapped.* keyed.txt partition-map.txt
+
+echo "testing folder restarts"
+seq 1 100000 > counted.txt
+# dies at once, then succeeds
+echo 0 > attempts.txt
+"$cwd/target/release/slb" \
+    --folder 'n=$(cat attempts.txt) ; echo $((n + 1)) > attempts.txt ; test "$n" -ge 1 && wc -l' \
+    --folder-restart 2 \
+    --infile counted.txt \
+    --outprefix "actual-restarted." 2>/dev/null
+test "$(cat attempts.txt)" -eq 2
+test "$(cat actual-restarted.0)" -gt 0
+rm actual-restarted.0 attempts.txt counted.txt
+
+echo "testing max chunks"
+mkdir many-files
+for i in $(seq 1 20) ; do
+    seq "$i" 5 200 > "many-files/$i.txt"
+done
+printf "unterminated" > many-files/last.txt
+"$cwd/target/release/slb" \
+    --mapper "cat" \
+    --folder "cat" \
+    --nthreads 8 \
+    --max-chunks 3 \
+    --dry-run \
+    --indir many-files > actual-max-chunks
+grep -qx "chunks: 3" actual-max-chunks
+grep -qx "nthreads: 8" actual-max-chunks
+"$cwd/target/release/slb" \
+    --mapper "cat" \
+    --folder "cat" \
+    --max-chunks 3 \
+    --indir many-files | sort > actual-max-chunks
+cat many-files/*.txt | sort | cmp - actual-max-chunks
+rm -r actual-max-chunks many-files
+
+echo "testing automatic buffer sizes"
+seq 1 1000 > counted.txt
+"$cwd/target/release/slb" \
+    --folder "wc -l" \
+    --auto-bufsize \
+    --verbose \
+    --infile counted.txt > actual-auto-count 2> actual-auto-bufsize
+grep -q "^auto bufsize: [0-9]* KB$" actual-auto-bufsize
+test "$(head -n 1 actual-auto-count)" -eq 1000
+rm actual-auto-bufsize actual-auto-count counted.txt
+
+echo "testing empty keys"
+for i in $(seq 1 100) ; do
+    echo "key$i value"
+    echo "  "
+done > blank-keys.txt
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --nfolders 3 \
+    --infile blank-keys.txt \
+    --outprefix "actual-blank."
+# blank lines all have the same, empty, key
+test "$(grep -lx "  " actual-blank.* | wc -l)" -eq 1
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --skip-empty-keys \
+    --discard-file actual-discarded \
+    --infile blank-keys.txt > actual-kept 2>/dev/null
+test "$(wc -l < actual-kept)" -eq 100
+test "$(grep -cx "  " actual-discarded)" -eq 100
+rm actual-blank.* actual-kept actual-discarded blank-keys.txt
+
+echo "testing mapper chunk environments"
+seq 1 100000 > counted.txt
+"$cwd/target/release/slb" \
+    --mapper 'cat > /dev/null ; echo "$SLB_CHUNK_INDEX $SLB_CHUNK_START $SLB_CHUNK_STOP $SLB_NCHUNKS"' \
+    --folder "cat" \
+    --nthreads 2 \
+    --mapper-env-per-chunk \
+    --infile counted.txt | sort > actual-chunk-env
+test "$(wc -l < actual-chunk-env)" -eq 2
+test "$(head -n 1 actual-chunk-env | cut -d " " -f 1,2,4)" = "0 0 2"
+test "$(tail -n 1 actual-chunk-env | cut -d " " -f 3)" -eq "$(wc -c < counted.txt)"
+rm actual-chunk-env counted.txt
+
+echo "testing folder partition environments"
+seq 1 1000 > counted.txt
+"$cwd/target/release/slb" \
+    --folder 'wc -l > "$SLB_OUTPUT_PATH.count" ; echo "$SLB_PARTITION_INDEX $SLB_NPARTITIONS"' \
+    --nfolders 2 \
+    --folder-env-per-partition \
+    --infile counted.txt \
+    --outprefix "actual-env."
+test "$(cat actual-env.0)" = "0 2"
+test "$(cat actual-env.1)" = "1 2"
+test "$(($(cat actual-env.0.count) + $(cat actual-env.1.count)))" -eq 1000
+rm actual-env.* counted.txt
+
+echo "testing output pipes"
+seq 1 1000 > counted.txt
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --nfolders 2 \
+    --pipe-output-through "sort -n" \
+    --infile counted.txt \
+    --outprefix "actual-piped."
+sort -n actual-piped.0 | cmp - actual-piped.0
+sort -n actual-piped.1 | cmp - actual-piped.1
+cat actual-piped.* | sort -n | cmp - counted.txt
+if "$cwd/target/release/slb" \
+    --folder "cat" \
+    --pipe-output-through "exit 4" \
+    --infile counted.txt > /dev/null 2>&1 ; then
+    echo "expected failure"
+    exit 1
+fi
+rm actual-piped.* counted.txt
+
+echo "testing key run splits"
+printf 'a 1\na 2\nb 1\na 3\nc 1\nc 2\nc 3\n' > runs.txt
+"$cwd/target/release/slb" \
+    --mapper "cat" \
+    --folder "cat" \
+    --nfolders 1 \
+    --combiner "wc -l" \
+    --split-on-key-change \
+    --infile runs.txt > actual-runs.txt
+printf '2\n1\n1\n3\n' | cmp - actual-runs.txt
+"$cwd/target/release/slb" \
+    --mapper "cat" \
+    --folder "cat" \
+    --nfolders 2 \
+    --split-on-key-change \
+    --infile runs.txt \
+    --outprefix "actual-runs."
+cat actual-runs.0 actual-runs.1 | sort | cmp - <(sort runs.txt)
+for f in actual-runs.0 actual-runs.1 ; do
+    test "$(cut -d' ' -f1 "$f" | grep -c '^a$' || true)" -eq 0 \
+        || test "$(cut -d' ' -f1 "$f" | grep -c '^a$')" -eq 3
+done
+rm actual-runs.* runs.txt
+
+echo "testing json output"
+printf 'a 1\nb "q"\nc\t3\n' > wrapped.txt
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --nfolders 2 \
+    --output-format json \
+    --infile wrapped.txt \
+    --outprefix "actual-json."
+printf '{"partition":0,"line":"b \\"q\\""}\n{"partition":0,"line":"c\\t3"}\n' \
+    | cmp - actual-json.0
+echo '{"partition":1,"line":"a 1"}' | cmp - actual-json.1
+rm actual-json.* wrapped.txt
+
+echo "testing key trimming"
+printf ' alice\t1\nalice \t2\nalice\t3\n alice \t4\nbob\t5\n' > padded.txt
+# no-break spaces are whitespace too
+printf '\xc2\xa0alice\xc2\xa0\t6\n' >> padded.txt
+for trim in --key-trim "--key-trim-start --key-trim-end" ; do
+    "$cwd/target/release/slb" \
+        --folder "cat" \
+        --nfolders 4 \
+        --delimiter '\t' \
+        $trim \
+        --infile padded.txt \
+        --outprefix "actual-trimmed."
+    test "$(grep -l alice actual-trimmed.* | wc -l)" -eq 1
+    cat actual-trimmed.* | sort | cmp - <(sort padded.txt)
+    rm actual-trimmed.*
+done
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --nfolders 4 \
+    --delimiter '\t' \
+    --key-trim-end \
+    --infile padded.txt \
+    --outprefix "actual-trimmed."
+test "$(grep -l '^alice' actual-trimmed.* | wc -l)" -eq 1
+test "$(grep -l '^ alice' actual-trimmed.* | wc -l)" -eq 1
+rm actual-trimmed.* padded.txt
+
+echo "testing key lowercasing"
+printf 'Alice 1\nALICE 2\nalice 3\nbob 4\nBob 5\n' > cased.txt
+# keys too long to lowercase on the stack
+long="$(printf 'Xy%.0s' $(seq 100))"
+printf '%s 6\n%s 7\n' "$long" "${long,,}" >> cased.txt
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --nfolders 4 \
+    --key-lowercase \
+    --infile cased.txt \
+    --outprefix "actual-lowercased."
+test "$(grep -il '^alice' actual-lowercased.* | wc -l)" -eq 1
+test "$(grep -il '^bob' actual-lowercased.* | wc -l)" -eq 1
+test "$(grep -il '^\(xy\)\{100\} ' actual-lowercased.* | wc -l)" -eq 1
+cat actual-lowercased.* | sort | cmp - <(sort cased.txt)
+rm actual-lowercased.* cased.txt
+
+echo "testing memory limits"
+seq 1 100 > limited.txt
+"$cwd/target/release/slb" \
+    --mapper "cat > /dev/null; ulimit -v" \
+    --folder "cat > /dev/null; ulimit -v" \
+    --nfolders 1 \
+    --max-mapper-memory 1024000000 \
+    --max-folder-memory 2048000000 \
+    --infile limited.txt > actual-limited.txt
+echo 2000000 | cmp - actual-limited.txt
+if "$cwd/target/release/slb" \
+    --folder 'cat > /dev/null; kill -9 $$' \
+    --max-folder-memory 1024000000 \
+    --infile limited.txt 2> actual-limited.txt ; then
+    echo "expected failure"
+    exit 1
+fi
+grep -q "likely over its memory limit" actual-limited.txt
+rm actual-limited.txt limited.txt
+
+echo "testing cpu affinity"
+seq 1 100 > pinned.txt
+"$cwd/target/release/slb" \
+    --mapper "cat > /dev/null; grep Cpus_allowed_list /proc/self/status" \
+    --folder "cat; grep Cpus_allowed_list /proc/self/status" \
+    --nfolders 1 \
+    --cpu-affinity manual:0x1 \
+    --infile pinned.txt > actual-pinned.txt
+printf 'Cpus_allowed_list:\t0\nCpus_allowed_list:\t0\n' | cmp - actual-pinned.txt
+"$cwd/target/release/slb" \
+    --folder "cat" \
+    --cpu-affinity auto \
+    --infile pinned.txt > actual-pinned.txt
+sort -n actual-pinned.txt | cmp - pinned.txt
+rm actual-pinned.txt pinned.txt
+
+echo "testing output per key"
+printf 'us 1\nfr 2\nus 3\na/b 4\nde 5\nsolo\n' > keyed.txt
+"$cwd/target/release/slb" \
+    --output-per-key \
+    --infile keyed.txt \
+    --outprefix "actual-keyed"
+printf 'us 1\nus 3\n' | cmp - actual-keyed_us.txt
+echo "fr 2" | cmp - actual-keyed_fr.txt
+echo "a/b 4" | cmp - actual-keyed_a%2Fb.txt
+echo "de 5" | cmp - actual-keyed_de.txt
+echo "solo" | cmp - actual-keyed_solo.txt
+test "$(ls actual-keyed_* | wc -l)" -eq 5
+rm actual-keyed_*
+if "$cwd/target/release/slb" \
+    --output-per-key \
+    --max-output-files 3 \
+    --infile keyed.txt \
+    --outprefix "actual-keyed" 2> /dev/null ; then
+    echo "expected failure"
+    exit 1
+fi
+test -z "$(ls actual-keyed_* 2> /dev/null)"
+rm keyed.txt
+
+echo "testing memory-mapped input"
+cargo build --release --features mmap --target-dir "$cwd/target/mmap" \
+    --manifest-path "$cwd/Cargo.toml"
+seq 1 1000 > mapped.txt
+echo header > mapped-header.txt
+: > mapped-empty.txt
+"$cwd/target/mmap/release/slb" \
+    --mapper "cat" \
+    --folder "cat" \
+    --mmap \
+    --infile mapped.txt > actual-mapped.txt
+sort -n actual-mapped.txt | cmp - mapped.txt
+# empty chunks, after skipping lines or of empty files, aren't mapped
+"$cwd/target/mmap/release/slb" \
+    --mapper "cat" \
+    --folder "cat" \
+    --mmap \
+    --input-skip-lines 1 \
+    --infile mapped-header.txt > actual-mapped.txt
+test ! -s actual-mapped.txt
+"$cwd/target/mmap/release/slb" \
+    --mapper "cat" \
+    --folder "cat" \
+    --mmap \
+    --infile mapped-empty.txt > actual-mapped.txt
+test ! -s actual-mapped.txt
+# shuffled input of over 1MB is dumped from a memory map
+seq 1 300000 > mapped-large.txt
+"$cwd/target/mmap/release/slb" \
+    --folder "cat" \
+    --mmap \
+    --input-shuffle \
+    --infile mapped-large.txt | sort -n > actual-mapped.txt
+cmp actual-mapped.txt mapped-large.txt
+rm actual-mapped.txt mapped.txt mapped-header.txt mapped-empty.txt mapped-large.txt
+
+echo "testing failing folders"
+if "$cwd/target/release/slb" \
+    --folder "exit 3" \
+    --infile nonl-1.txt \
+    --outprefix "actual-fail." 2>/dev/null ; then
+    echo "expected failure"
+    exit 1
+fi
+test -z "$(ls actual-fail.* 2>/dev/null)"
+
+echo "testing folder timeouts"
+if "$cwd/target/release/slb" \
+    --folder "sleep 100" \
+    --folder-timeout 0.5 \
+    --infile nonl-1.txt \
+    --outprefix "actual-timeout." 2>/dev/null ; then
+    echo "expected timeout"
+    exit 1
+fi
+test -z "$(ls actual-timeout.* 2>/dev/null)"
 popd >/dev/null